@@ -1,7 +1,7 @@
 use crate::codec::{
-    ClientMqCodec, MessageData,
-    MessageProtocol::{Pub, ReqRep, Sub, UnSub},
-    MqRequest, MqResponse,
+    BoxKey, Capability, ClientMqCodec, Hello, MessageBody, MessageData,
+    MessageProtocol::{Pub, Reply, ReqRep, Sub, UnSub},
+    MqError, MqRequest, MqResponse, WireFormat, WireFormatHandle, PROTOCOL_VERSION,
 };
 use crate::server;
 use crate::sign;
@@ -14,6 +14,7 @@ use serde_derive::{Deserialize, Serialize};
 use serde_json as json;
 use sodiumoxide::crypto::box_;
 use sodiumoxide::crypto::sign::ed25519::PublicKey;
+use std::collections::HashMap;
 use std::str::FromStr;
 use std::time::Duration;
 use std::time::SystemTime;
@@ -26,6 +27,9 @@ use uuid::Uuid;
 
 const PING_TIME_SEC: u64 = 5;
 
+/// Content type of client message body
+const JSON_CONTENT_TYPE: &str = "application/json";
+
 /// Basic type for MQ Client
 pub struct MqClient {
     pub config: ClientAppConfig,
@@ -35,6 +39,18 @@ pub struct MqClient {
 struct MqClientConnection {
     framed: actix::io::FramedWrite<WriteHalf<TcpStream>, ClientMqCodec>,
     settings: ClientAppConfig,
+    /// Wire format for sending to Node
+    format: WireFormatHandle,
+    /// Verified peers box_ PublicKeys
+    box_keys: HashMap<PublicKey, box_::PublicKey>,
+    /// Outgoing messages waiting for recipient box_ PublicKey
+    pending_out: HashMap<PublicKey, Vec<MessageData>>,
+    /// Incoming messages waiting for sender box_ PublicKey
+    pending_in: HashMap<PublicKey, Vec<server::MqMessage>>,
+    /// Capabilities enabled by Node
+    capabilities: Vec<Capability>,
+    /// Last sent message for retry
+    last_sent: Option<MessageData>,
 }
 
 /// Struct for client message
@@ -55,6 +71,18 @@ impl Actor for MqClientConnection {
     type Context = Context<Self>;
 
     fn started(&mut self, ctx: &mut Context<Self>) {
+        // Hello should be sent before any other request
+        self.framed.write(MqRequest::Hello(Hello {
+            version: PROTOCOL_VERSION,
+            capabilities: vec![
+                Capability::KeyDirectory,
+                Capability::Acks,
+                Capability::LastWill,
+                Capability::Presence,
+            ],
+            formats: self.formats(),
+        }));
+
         // start heartbeats otherwise server will disconnect after 10 seconds
         self.hb(ctx)
     }
@@ -94,13 +122,25 @@ impl MqClient {
                     .and_then(move |stream| {
                         let addr = MqClientConnection::create(move |ctx| {
                             let (r, w) = stream.split();
-                            ctx.add_stream(FramedRead::new(r, ClientMqCodec));
+                            let max_frame_size = client_config.node.max_frame_size;
+                            let format = WireFormatHandle::default();
+                            ctx.add_stream(FramedRead::new(r, ClientMqCodec::new(max_frame_size)));
                             ctx.add_message_stream(once(Ok(RegisterCommand(
                                 client_config.public_key,
                             ))));
                             MqClientConnection {
-                                framed: actix::io::FramedWrite::new(w, ClientMqCodec, ctx),
+                                framed: actix::io::FramedWrite::new(
+                                    w,
+                                    ClientMqCodec::with_format(max_frame_size, format.clone()),
+                                    ctx,
+                                ),
                                 settings: client_config,
+                                format,
+                                box_keys: HashMap::new(),
+                                pending_out: HashMap::new(),
+                                pending_in: HashMap::new(),
+                                capabilities: vec![],
+                                last_sent: None,
                             }
                         });
 
@@ -136,9 +176,177 @@ impl MqClientConnection {
             act.hb(ctx);
         });
     }
+
+    /// Wire formats in order of preference, Json is
+    /// fallback for Node not supporting preferred format
+    fn formats(&self) -> Vec<WireFormat> {
+        let mut formats = vec![self.settings.node.wire_format];
+        if !formats.contains(&WireFormat::Json) {
+            formats.push(WireFormat::Json);
+        }
+        formats
+    }
+
+    /// Sign and send message to Node.
+    /// REQ/REP request and reply body is encoded for
+    /// recipient box_ PublicKey when encoding is enabled.
+    fn send_message(&mut self, mut msg: MessageData) {
+        if self.settings.message.encode && matches!(msg.protocol, ReqRep | Reply { .. }) {
+            let to = msg.to.expect("REQ/REP message should have recipient");
+            let key = match self.box_keys.get(&to) {
+                Some(key) => key,
+                None => {
+                    // Wait for recipient key from Node key directory
+                    let pending = self.pending_out.entry(to).or_default();
+                    if pending.is_empty() {
+                        self.framed.write(MqRequest::LookupKey(to));
+                    }
+                    pending.push(msg);
+                    return;
+                }
+            };
+
+            let nonce = box_::gen_nonce();
+            let encoded_msg = box_::seal(
+                msg.body.as_bytes(),
+                &nonce,
+                key,
+                &self.settings.message.secret_key,
+            );
+
+            msg.body = MessageBody(encoded_msg);
+            msg.nonce = Some(nonce);
+        }
+
+        self.sign_message(&mut msg);
+        self.last_sent = Some(msg.clone());
+        self.framed.write(MqRequest::Message(Box::new(msg)));
+    }
+
+    /// Set message sign
+    fn sign_message(&self, msg: &mut MessageData) {
+        msg.signature = if self.settings.message.sign {
+            Some(sign::sign_message(
+                msg,
+                &self.settings.public_key,
+                &self.settings.secret_key,
+            ))
+        } else {
+            None
+        };
+    }
+
+    /// Acknowledge received message, otherwise
+    /// Node redelivers message
+    fn ack(&mut self, msg: &server::MqMessage) {
+        if self.capabilities.contains(&Capability::Acks) {
+            self.framed.write(MqRequest::Ack(msg.from, msg.id.clone()));
+        }
+    }
+
+    /// Handle message from Node.
+    /// Encoded message body is decoded with sender box_ PublicKey.
+    fn receive_message(&mut self, msg: server::MqMessage) {
+        let body = match msg.nonce {
+            Some(ref nonce) if !matches!(msg.protocol, Pub { .. }) => {
+                let key = match self.box_keys.get(&msg.from) {
+                    Some(key) => key,
+                    None => {
+                        // Wait for sender key from Node key directory
+                        let from = msg.from;
+                        let pending = self.pending_in.entry(from).or_default();
+                        if pending.is_empty() {
+                            self.framed.write(MqRequest::LookupKey(from));
+                        }
+                        pending.push(msg);
+                        return;
+                    }
+                };
+
+                match box_::open(
+                    msg.body.as_bytes(),
+                    nonce,
+                    key,
+                    &self.settings.message.secret_key,
+                ) {
+                    Ok(data) => data,
+                    Err(_) => {
+                        eprintln!("Message can't be decoded: {}", msg.id);
+                        // Redelivered message can't be decoded too
+                        self.ack(&msg);
+                        return;
+                    }
+                }
+            }
+            _ => msg.body.0.clone(),
+        };
+
+        let is_verified = msg.verify();
+        println!("message: {:#?}", msg);
+        println!("is verified: {:#?}", is_verified);
+
+        // Message without content type is expected to be JSON,
+        // other bodies can't be handled by this client
+        let client_msg: ClientMessageData = match msg.content_type.as_deref() {
+            Some(JSON_CONTENT_TYPE) | None => match json::from_slice(&body) {
+                Ok(client_msg) => client_msg,
+                Err(err) => {
+                    eprintln!("Message body can't be decoded: {}: {}", msg.id, err);
+                    // Redelivered message can't be decoded too
+                    self.ack(&msg);
+                    return;
+                }
+            },
+            Some(content_type) => {
+                println!("Message body: {} bytes of {}", body.len(), content_type);
+                self.ack(&msg);
+                return;
+            }
+        };
+        dbg!(&client_msg);
+        self.ack(&msg);
+
+        // Send message response data and reply for ReqRep
+        if msg.protocol == ReqRep {
+            self.framed
+                .write(MqRequest::MessageResponse(server::MqMessageResponse {
+                    from: msg.from,
+                    to: msg.to,
+                    id: msg.id.clone(),
+                    status: server::MessageSendStatus::Received,
+                }));
+
+            let reply_data = json::to_string(&ClientMessageData {
+                title: format!("reply for {}", client_msg.title),
+                amount: client_msg.amount,
+            })
+            .expect("Message should be serialize to JSON");
+
+            self.send_message(MessageData {
+                id: Uuid::new_v4().to_string(),
+                to: Some(msg.from),
+                signature: None,
+                event: None,
+                protocol: Reply {
+                    correlation_id: msg.id,
+                },
+                time: SystemTime::now(),
+                nonce: None,
+                body: reply_data.into(),
+                content_type: Some(JSON_CONTENT_TYPE.to_owned()),
+                expires_at: None,
+                deliver_at: None,
+            });
+        }
+    }
 }
 
-impl actix::io::WriteHandler<io::Error> for MqClientConnection {}
+impl actix::io::WriteHandler<io::Error> for MqClientConnection {
+    fn error(&mut self, err: io::Error, _: &mut Context<Self>) -> Running {
+        eprintln!("Node connection write error: {}", err);
+        Running::Stop
+    }
+}
 
 /// Handle Register commands
 impl Handler<RegisterCommand> for MqClientConnection {
@@ -185,7 +393,7 @@ impl Handler<ClientCommand> for MqClientConnection {
                             })
                             .expect("Message should be serialize to JSON");
 
-                            let mut msg = MessageData {
+                            let msg = MessageData {
                                 id: Uuid::new_v4().to_string(),
                                 to: Some(client1_pk),
                                 signature: None,
@@ -193,33 +401,13 @@ impl Handler<ClientCommand> for MqClientConnection {
                                 protocol: ReqRep,
                                 time: SystemTime::now(),
                                 nonce: None,
-                                body: msg_data,
+                                body: msg_data.into(),
+                                content_type: Some(JSON_CONTENT_TYPE.to_owned()),
+                                expires_at: None,
+                                deliver_at: None,
                             };
 
-                            if self.settings.message.encode {
-                                let nonce = box_::gen_nonce();
-                                let encoded_msg = box_::seal(
-                                    &msg.body.as_bytes(),
-                                    &nonce,
-                                    &self.settings.message.public_key,
-                                    &self.settings.message.secret_key,
-                                );
-
-                                msg.body = sign::to_hex(&encoded_msg);
-                                msg.nonce = Some(nonce);
-                            }
-
-                            let data =
-                                json::to_string(&msg).expect("Message should be serialize to JSON");
-
-                            // Set message sign
-                            msg.signature = if self.settings.message.sign {
-                                Some(sign::sign(data.as_bytes(), &self.settings.secret_key))
-                            } else {
-                                None
-                            };
-
-                            self.framed.write(MqRequest::Message(msg));
+                            self.send_message(msg);
                         }
                         "client2" => {
                             let msg_data = json::to_string(&ClientMessageData {
@@ -228,7 +416,7 @@ impl Handler<ClientCommand> for MqClientConnection {
                             })
                             .expect("Message should be serialize to JSON");
 
-                            let mut msg = MessageData {
+                            let msg = MessageData {
                                 id: Uuid::new_v4().to_string(),
                                 to: Some(client2_pk),
                                 signature: None,
@@ -236,33 +424,13 @@ impl Handler<ClientCommand> for MqClientConnection {
                                 protocol: ReqRep,
                                 time: SystemTime::now(),
                                 nonce: None,
-                                body: msg_data,
+                                body: msg_data.into(),
+                                content_type: Some(JSON_CONTENT_TYPE.to_owned()),
+                                expires_at: None,
+                                deliver_at: None,
                             };
 
-                            if self.settings.message.encode {
-                                let nonce = box_::gen_nonce();
-                                let encoded_msg = box_::seal(
-                                    &msg.body.as_bytes(),
-                                    &nonce,
-                                    &self.settings.message.public_key,
-                                    &self.settings.message.secret_key,
-                                );
-
-                                msg.body = sign::to_hex(&encoded_msg);
-                                msg.nonce = Some(nonce);
-                            }
-
-                            let data =
-                                json::to_string(&msg).expect("Message should be serialize to JSON");
-
-                            // Set message sign
-                            msg.signature = if self.settings.message.sign {
-                                Some(sign::sign(data.as_bytes(), &self.settings.secret_key))
-                            } else {
-                                None
-                            };
-
-                            self.framed.write(MqRequest::Message(msg));
+                            self.send_message(msg);
                         }
                         _ => println!(">> Wrong /reqrep command. For help print: /help"),
                     }
@@ -272,7 +440,37 @@ impl Handler<ClientCommand> for MqClientConnection {
                         println!(">> Wrong /pub command. For help print: /help");
                         return;
                     }
-                    let event_name = Some(v[1].to_owned());
+                    let args: Vec<&str> = v[1].split_whitespace().collect();
+                    let now = SystemTime::now();
+                    let (mut deliver_at, mut expires_at) = (None, None);
+                    let (mut retain, mut clear) = (false, false);
+                    let mut options = args[1..].iter();
+                    while let Some(option) = options.next() {
+                        let time = match *option {
+                            "retain" => {
+                                retain = true;
+                                continue;
+                            }
+                            "clear" => {
+                                clear = true;
+                                continue;
+                            }
+                            "delay" => &mut deliver_at,
+                            "ttl" => &mut expires_at,
+                            _ => {
+                                println!(">> Wrong /pub command. For help print: /help");
+                                return;
+                            }
+                        };
+                        match options.next().and_then(|sec| sec.parse().ok()) {
+                            Some(sec) => *time = Some(now + Duration::from_secs(sec)),
+                            None => {
+                                println!(">> Wrong /pub command. For help print: /help");
+                                return;
+                            }
+                        }
+                    }
+                    let event_name = args.first().map(|name| (*name).to_owned());
 
                     let msg_data = json::to_string(&ClientMessageData {
                         title: format!(
@@ -284,57 +482,64 @@ impl Handler<ClientCommand> for MqClientConnection {
                     })
                     .expect("Message should be serialize to JSON");
 
+                    // Empty retained message clears retained value
+                    let (body, content_type) = if clear {
+                        (MessageBody::default(), None)
+                    } else {
+                        (msg_data.into(), Some(JSON_CONTENT_TYPE.to_owned()))
+                    };
+
                     // Public message not encode message body
-                    let mut msg = MessageData {
+                    let msg = MessageData {
                         id: Uuid::new_v4().to_string(),
                         to: None,
                         signature: None,
                         event: event_name,
-                        protocol: Pub,
-                        time: SystemTime::now(),
+                        protocol: Pub {
+                            retain: retain || clear,
+                        },
+                        time: now,
                         nonce: None,
-                        body: msg_data,
+                        body,
+                        content_type,
+                        expires_at,
+                        deliver_at,
                     };
 
-                    let data = json::to_string(&msg).expect("Message should be serialize to JSON");
-
-                    // Set message sign
-                    msg.signature = if self.settings.message.sign {
-                        Some(sign::sign(data.as_bytes(), &self.settings.secret_key))
-                    } else {
-                        None
-                    };
-
-                    self.framed.write(MqRequest::Message(msg));
+                    self.send_message(msg);
                 }
                 "/sub" => {
                     if v.len() < 2 {
                         println!(">> Wrong /sub command. For help print: /help");
                         return;
                     }
-                    let event_name = Some(v[1].to_owned());
+                    let args: Vec<&str> = v[1].split_whitespace().collect();
+                    let (durable, group) = match args[1..] {
+                        [] => (false, None),
+                        ["durable"] => (true, None),
+                        ["group", group] => (false, Some(group.to_owned())),
+                        _ => {
+                            println!(">> Wrong /sub command. For help print: /help");
+                            return;
+                        }
+                    };
+                    let event_name = args.first().map(|name| (*name).to_owned());
 
-                    let mut msg = MessageData {
+                    let msg = MessageData {
                         id: Uuid::new_v4().to_string(),
                         to: None,
                         signature: None,
                         event: event_name,
-                        protocol: Sub,
+                        protocol: Sub { durable, group },
                         time: SystemTime::now(),
                         nonce: None,
-                        body: String::from(""),
-                    };
-
-                    let data = json::to_string(&msg).expect("Message should be serialize to JSON");
-
-                    // Set message sign
-                    msg.signature = if self.settings.message.sign {
-                        Some(sign::sign(data.as_bytes(), &self.settings.secret_key))
-                    } else {
-                        None
+                        body: MessageBody::default(),
+                        content_type: None,
+                        expires_at: None,
+                        deliver_at: None,
                     };
 
-                    self.framed.write(MqRequest::Message(msg));
+                    self.send_message(msg);
                 }
                 "/unsub" => {
                     if v.len() < 2 {
@@ -343,7 +548,7 @@ impl Handler<ClientCommand> for MqClientConnection {
                     }
                     let event_name = Some(v[1].to_owned());
 
-                    let mut msg = MessageData {
+                    let msg = MessageData {
                         id: Uuid::new_v4().to_string(),
                         to: None,
                         signature: None,
@@ -351,19 +556,13 @@ impl Handler<ClientCommand> for MqClientConnection {
                         protocol: UnSub,
                         time: SystemTime::now(),
                         nonce: None,
-                        body: String::from(""),
+                        body: MessageBody::default(),
+                        content_type: None,
+                        expires_at: None,
+                        deliver_at: None,
                     };
 
-                    let data = json::to_string(&msg).expect("Message should be serialize to JSON");
-
-                    // Set message sign
-                    msg.signature = if self.settings.message.sign {
-                        Some(sign::sign(data.as_bytes(), &self.settings.secret_key))
-                    } else {
-                        None
-                    };
-
-                    self.framed.write(MqRequest::Message(msg));
+                    self.send_message(msg);
                 }
                 "/ping" => {
                     if v.len() < 2 {
@@ -383,6 +582,75 @@ impl Handler<ClientCommand> for MqClientConnection {
                         }
                     }
                 }
+                "/will" => {
+                    if v.len() < 2 {
+                        println!(">> Wrong /will command. For help print: /help");
+                        return;
+                    }
+                    let args: Vec<&str> = v[1].split_whitespace().collect();
+                    let retain = match args[1..] {
+                        [] => false,
+                        ["retain"] => true,
+                        _ => {
+                            println!(">> Wrong /will command. For help print: /help");
+                            return;
+                        }
+                    };
+                    let event_name = args.first().map(|name| (*name).to_owned());
+
+                    let msg_data = json::to_string(&ClientMessageData {
+                        title: format!(
+                            "Last will from: {}",
+                            sign::to_hex(&self.settings.public_key[..])
+                        ),
+                        amount: 0,
+                    })
+                    .expect("Message should be serialize to JSON");
+
+                    let mut msg = MessageData {
+                        id: Uuid::new_v4().to_string(),
+                        to: None,
+                        signature: None,
+                        event: event_name,
+                        protocol: Pub { retain },
+                        time: SystemTime::now(),
+                        nonce: None,
+                        body: msg_data.into(),
+                        content_type: Some(JSON_CONTENT_TYPE.to_owned()),
+                        expires_at: None,
+                        deliver_at: None,
+                    };
+                    self.sign_message(&mut msg);
+                    self.framed.write(MqRequest::LastWill(Some(Box::new(msg))));
+                }
+                "/quit" => self.framed.write(MqRequest::Disconnect),
+                "/presence" | "/watch" | "/unwatch" => {
+                    let mut keys = vec![];
+                    for name in v.get(1).unwrap_or(&"").split_whitespace() {
+                        match name {
+                            "client1" => keys.push(client1_pk),
+                            "client2" => keys.push(client2_pk),
+                            _ => {
+                                println!("Unknown client name. Print for help: /help");
+                                return;
+                            }
+                        }
+                    }
+                    if keys.is_empty() {
+                        println!(">> Wrong {} command. For help print: /help", v[0]);
+                        return;
+                    }
+                    self.framed.write(match v[0] {
+                        "/presence" => MqRequest::Presence(keys),
+                        "/watch" => MqRequest::PresenceSub(keys),
+                        _ => MqRequest::PresenceUnSub(keys),
+                    });
+                }
+                "/retry" => match self.last_sent.clone() {
+                    // Node drops retried message if it was accepted
+                    Some(msg) => self.framed.write(MqRequest::Message(Box::new(msg))),
+                    None => println!(">> No message to retry"),
+                },
                 "/help" => {
                     println!(
                         r#"Commands HELP:
@@ -391,6 +659,17 @@ impl Handler<ClientCommand> for MqClientConnection {
                         Available clients name: client1, client2
 
     /help               print this help
+    /retry              send last message again with same id
+    /will [NAME]        set last-will PUB message for specific channel name,
+                        Node publishes it if connection is lost.
+                        Option: `retain` - retain last-will message.
+                        Example: /will my.status.channel retain
+    /quit               disconnect without last-will message
+
+    /presence [CLIENT..] check are clients online.
+                        Available clients name: client1, client2
+    /watch [CLIENT..]   subscribe to clients online/offline changes
+    /unwatch [CLIENT..] unsubscribe from clients online/offline changes
     [CLIENT] [MESSAGE]  send message to specific client.
                         Available clients name: client1, client2
 
@@ -399,9 +678,23 @@ impl Handler<ClientCommand> for MqClientConnection {
 
     /pub [NAME]         send PUB message for specific channel name.
                         Example: /pub my.public.channel
+                        Options: `delay SECONDS` - deliver message later,
+                        `ttl SECONDS` - discard message after timeout,
+                        `retain` - keep message for new subscribers,
+                        `clear` - clear retained message.
+                        Example: /pub my.public.channel delay 5 ttl 60
 
     /sub [NAME]         send SUB message to subscribe for specific channel name.
-                        Example: /sub my.public.channel
+                        Name can contain wildcards: `*` for one segment,
+                        `>` or `#` for one or more last segments.
+                        Example: /sub my.public.channel, /sub my.*.channel
+    /sub [NAME] durable subscribe and receive messages published while offline.
+                        Example: /sub my.public.channel durable
+
+    /sub [NAME] group [GROUP]
+                        subscribe as queue group member, every message is
+                        received by one group member.
+                        Example: /sub my.public.channel group workers
 
     /unsub [NAME]       send UNSUB message to unsubscrive from specific channel name.
                         Example: /unsub my.public.channel
@@ -419,42 +712,14 @@ impl Handler<ClientCommand> for MqClientConnection {
 
 /// Server communication
 impl StreamHandler<MqResponse, io::Error> for MqClientConnection {
-    fn handle(&mut self, msg: MqResponse, _: &mut Context<Self>) {
-        match msg {
-            MqResponse::Message(mut msg) => {
-                let is_verified = msg.verify();
-                println!("message: {:#?}", msg);
-                println!("is verified: {:#?}", is_verified);
-
-                // Encode message
-                if msg.protocol != Pub && self.settings.message.encode {
-                    let encoded_msg = box_::open(
-                        &sign::from_hex(&msg.body),
-                        &msg.nonce.unwrap(),
-                        &self.settings.message.public_key,
-                        &self.settings.message.secret_key,
-                    )
-                    .expect("Message should be decoded.");
-
-                    let msg_data = std::str::from_utf8(&encoded_msg[..])
-                        .expect("Message should be valid UTF8 string");
-                    let client_msg: ClientMessageData = json::from_str(&msg_data).unwrap();
-                    dbg!(client_msg);
-                } else {
-                    let client_msg: ClientMessageData = json::from_str(&msg.body).unwrap();
-                    dbg!(client_msg);
-                }
+    fn error(&mut self, err: io::Error, _: &mut Context<Self>) -> Running {
+        eprintln!("Node connection error: {}", err);
+        Running::Stop
+    }
 
-                // Send message response data for ReqRep
-                if msg.protocol == ReqRep {
-                    self.framed
-                        .write(MqRequest::MessageResponse(server::MqMessageResponse {
-                            from: msg.from,
-                            to: msg.to,
-                            status: server::MessageSendStatus::Received,
-                        }));
-                }
-            }
+    fn handle(&mut self, msg: MqResponse, ctx: &mut Context<Self>) {
+        match msg {
+            MqResponse::Message(msg) => self.receive_message(*msg),
             MqResponse::Pong => {}
             MqResponse::PingClient(pk) => {
                 println!("PingClient");
@@ -466,6 +731,87 @@ impl StreamHandler<MqResponse, io::Error> for MqClientConnection {
             MqResponse::MessageResponseStatus(status) => {
                 println!("MessageResponseStatus: {:#?}", status);
             }
+            MqResponse::RegisterChallenge(challenge) => {
+                // Short nonce makes signature reusable by other Node
+                if challenge.nonce.len() != sign::CHALLENGE_NONCE_BYTES {
+                    eprintln!("Register challenge: invalid nonce length");
+                    ctx.stop();
+                    return;
+                }
+                // Prove ownership of client pub_key
+                let data = challenge.sign_data(&self.settings.public_key);
+                let signature = sign::sign(&data, &self.settings.secret_key);
+                self.framed.write(MqRequest::RegisterProof(signature));
+            }
+            MqResponse::Registered(pk) => {
+                println!("Registered: {}", sign::to_hex_pk(&pk));
+                // Publish box_ PublicKey for peers message encoding
+                self.framed.write(MqRequest::PublishKey(BoxKey::new(
+                    self.settings.message.public_key,
+                    &self.settings.secret_key,
+                )));
+            }
+            MqResponse::BoxKey(pk, key) => match key {
+                Some(ref key) if key.verify(&pk) => {
+                    self.box_keys.insert(pk, key.key);
+                    // Process messages waiting for peer key
+                    for msg in self.pending_out.remove(&pk).unwrap_or_default() {
+                        self.send_message(msg);
+                    }
+                    for msg in self.pending_in.remove(&pk).unwrap_or_default() {
+                        self.receive_message(msg);
+                    }
+                }
+                _ => {
+                    eprintln!("Box key not found for: {}", sign::to_hex_pk(&pk));
+                    self.pending_out.remove(&pk);
+                    // Messages can't be decoded, so they are
+                    // acknowledged to stop redelivery
+                    for msg in self.pending_in.remove(&pk).unwrap_or_default() {
+                        self.ack(&msg);
+                    }
+                }
+            },
+            MqResponse::Hello(hello) => {
+                println!("Hello: {:?}", hello);
+                if hello.version != PROTOCOL_VERSION {
+                    eprintln!(
+                        "Node protocol version {} not supported (expected {})",
+                        hello.version, PROTOCOL_VERSION
+                    );
+                    ctx.stop();
+                    return;
+                }
+                self.format.set(hello.format);
+                self.capabilities = hello.capabilities;
+            }
+            MqResponse::Presence(statuses) => {
+                for status in statuses {
+                    println!(
+                        "Presence: {} online: {}",
+                        sign::to_hex_pk(&status.pub_key),
+                        status.online
+                    );
+                }
+            }
+            MqResponse::PresenceChanged(status) => {
+                println!(
+                    "Presence changed: {} online: {}",
+                    sign::to_hex_pk(&status.pub_key),
+                    status.online
+                );
+            }
+            MqResponse::Error(MqError::UnsupportedVersion(version)) => {
+                eprintln!(
+                    "Protocol version {} not supported by Node (Node version {})",
+                    PROTOCOL_VERSION, version
+                );
+                ctx.stop();
+            }
+            MqResponse::Error(err) => {
+                eprintln!("Node error: {:?}", err);
+                ctx.stop();
+            }
         }
     }
 }
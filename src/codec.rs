@@ -2,20 +2,31 @@
 use actix::Message;
 use byteorder::{BigEndian, ByteOrder};
 use bytes::{BufMut, BytesMut};
+use futures::Poll;
+use serde::de::{self, Deserializer, MapAccess, SeqAccess, Visitor};
+use serde::ser::{SerializeMap, Serializer};
 use serde_derive::{Deserialize, Serialize};
 use serde_json as json;
 use sodiumoxide::crypto::box_ as cipher;
-use sodiumoxide::crypto::sign::ed25519::{PublicKey, Signature};
+use sodiumoxide::crypto::sign::ed25519::{PublicKey, SecretKey, Signature};
+use std::cell::Cell;
+use std::fmt;
 use std::io;
+use std::marker::PhantomData;
+use std::rc::Rc;
 use std::time::SystemTime;
 use tokio_io::codec::{Decoder, Encoder};
+use tokio_io::AsyncWrite;
 
 use crate::server;
+use crate::sign;
 
 /// Client request
 #[derive(Serialize, Deserialize, Debug, Message)]
 #[serde(tag = "cmd", content = "data")]
 pub enum MqRequest {
+    /// Hello request - should be first request for session
+    Hello(Hello),
     /// Ping request
     Ping,
     /// Ping client by pub_key
@@ -23,25 +34,58 @@ pub enum MqRequest {
     /// Pong from client by pub_key
     PongClient(PublicKey),
     /// Send message
-    Message(MessageData),
+    Message(Box<MessageData>),
     /// Register request
     Register(PublicKey),
+    /// Register challenge signature proof
+    RegisterProof(Signature),
+    /// Publish client box_ PublicKey to Node key directory
+    PublishKey(BoxKey),
+    /// Lookup client box_ PublicKey in Node key directory
+    LookupKey(PublicKey),
     /// Message Response request
     MessageResponse(server::MqMessageResponse),
+    /// Acknowledge received message by sender and message id
+    Ack(PublicKey, String),
+    /// Set PUB message published by Node when session ends
+    /// without clean disconnect, `None` clears last-will message
+    LastWill(Option<Box<MessageData>>),
+    /// Clean disconnect, last-will message is not published
+    Disconnect,
+    /// Query clients presence
+    Presence(Vec<PublicKey>),
+    /// Subscribe to clients presence changes
+    PresenceSub(Vec<PublicKey>),
+    /// Unsubscribe from clients presence changes
+    PresenceUnSub(Vec<PublicKey>),
 }
 
 /// Basic MQ message target/type
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[serde(tag = "cmd", content = "data")]
 pub enum MessageProtocol {
-    /// Publish for PUB/SUB protocol
-    Pub,
-    /// Subscribe for PUB/SUB protocol
-    Sub,
+    /// Publish for PUB/SUB protocol. Retained message is
+    /// kept by Node and sent to every new subscriber,
+    /// empty retained message clears kept message.
+    Pub {
+        #[serde(default)]
+        retain: bool,
+    },
+    /// Subscribe for PUB/SUB protocol. Durable subscriber
+    /// receives PUB messages published while it was offline.
+    /// Subscribers with same group receive messages round-robin.
+    Sub {
+        #[serde(default)]
+        durable: bool,
+        #[serde(default)]
+        group: Option<String>,
+    },
     /// Unsubscribe for PUB/SUB protocol
     UnSub,
     /// Request / Response protocol
     ReqRep,
+    /// Reply for REQ/REP request, `correlation_id` is request `id`
+    Reply { correlation_id: String },
 }
 
 /// Basic MQ Message Data
@@ -54,7 +98,16 @@ pub struct MessageData {
     pub protocol: MessageProtocol,
     pub time: SystemTime,
     pub nonce: Option<cipher::Nonce>,
-    pub body: String,
+    pub body: MessageBody,
+    /// Body content type, for example `application/json`
+    #[serde(default)]
+    pub content_type: Option<String>,
+    /// Node discards message not delivered before this time
+    #[serde(default)]
+    pub expires_at: Option<SystemTime>,
+    /// Node holds message until this time before routing
+    #[serde(default)]
+    pub deliver_at: Option<SystemTime>,
 }
 
 impl MessageData {
@@ -70,10 +123,117 @@ impl MessageData {
             time: self.time,
             nonce: self.nonce,
             body: self.body.clone(),
+            content_type: self.content_type.clone(),
+            expires_at: self.expires_at,
+            deliver_at: self.deliver_at,
+            redelivered: 0,
         }
     }
 }
 
+/// Message body raw bytes.
+///
+/// Binary wire formats send body as bytes. Human-readable formats
+/// (JSON) send valid UTF-8 body as string and other data as
+/// `{"base64": "..."}` object, so existing string bodies
+/// stay compatible.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct MessageBody(pub Vec<u8>);
+
+impl MessageBody {
+    /// Body as UTF-8 string, if it is valid UTF-8
+    pub fn as_str(&self) -> Option<&str> {
+        std::str::from_utf8(&self.0).ok()
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl From<Vec<u8>> for MessageBody {
+    fn from(data: Vec<u8>) -> Self {
+        MessageBody(data)
+    }
+}
+
+impl From<String> for MessageBody {
+    fn from(data: String) -> Self {
+        MessageBody(data.into_bytes())
+    }
+}
+
+impl<'a> From<&'a str> for MessageBody {
+    fn from(data: &'a str) -> Self {
+        MessageBody(data.as_bytes().to_vec())
+    }
+}
+
+impl serde::Serialize for MessageBody {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if !serializer.is_human_readable() {
+            return serializer.serialize_bytes(&self.0);
+        }
+        match self.as_str() {
+            Some(data) => serializer.serialize_str(data),
+            None => {
+                let mut map = serializer.serialize_map(Some(1))?;
+                map.serialize_entry("base64", &base64::encode(&self.0))?;
+                map.end()
+            }
+        }
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for MessageBody {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct BodyVisitor;
+
+        impl<'de> Visitor<'de> for BodyVisitor {
+            type Value = MessageBody;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("string, bytes or base64 object")
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+                Ok(MessageBody::from(v))
+            }
+
+            fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Self::Value, E> {
+                Ok(MessageBody(v.to_vec()))
+            }
+
+            fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<Self::Value, E> {
+                Ok(MessageBody(v))
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+                let mut data = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+                while let Some(byte) = seq.next_element()? {
+                    data.push(byte);
+                }
+                Ok(MessageBody(data))
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+                match map.next_entry::<String, String>()? {
+                    Some((ref key, ref data)) if key == "base64" => base64::decode(data)
+                        .map(MessageBody)
+                        .map_err(de::Error::custom),
+                    _ => Err(de::Error::custom("expected base64 body object")),
+                }
+            }
+        }
+
+        deserializer.deserialize_any(BodyVisitor)
+    }
+}
+
 /// Server response
 #[derive(Serialize, Deserialize, Debug, Message)]
 #[serde(tag = "cmd", content = "data")]
@@ -81,93 +241,520 @@ pub enum MqResponse {
     /// Pong response
     Pong,
     /// Receive Message
-    Message(server::MqMessage),
+    Message(Box<server::MqMessage>),
     /// Ping message for Client
     PingClient(PublicKey),
     /// Pong message for Client
     PongClient(PublicKey),
     /// Message response status
     MessageResponseStatus(server::MqMessageResponse),
+    /// Register challenge for Client key ownership proof
+    RegisterChallenge(RegisterChallenge),
+    /// Client successfully registered
+    Registered(PublicKey),
+    /// Key directory lookup result for client pub_key
+    BoxKey(PublicKey, Option<BoxKey>),
+    /// Request failed - session will be closed
+    Error(MqError),
+    /// Hello response with session parameters
+    Hello(HelloAck),
+    /// Clients presence for Presence and PresenceSub requests
+    Presence(Vec<PresenceStatus>),
+    /// Subscribed client presence changed
+    PresenceChanged(PresenceStatus),
 }
 
-/// Codec for Client -> Server transport
-pub struct MqCodec;
+/// Optional protocol features negotiated per session
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(tag = "name")]
+pub enum Capability {
+    /// Box PublicKey directory for message encoding
+    KeyDirectory,
+    /// Client acknowledges received messages,
+    /// unacknowledged messages are redelivered
+    Acks,
+    /// Client sets last-will message
+    LastWill,
+    /// Client queries and subscribes to peers presence
+    Presence,
+    /// Capability unknown for current peer
+    #[serde(other)]
+    Unknown,
+}
 
-impl Decoder for MqCodec {
-    type Item = MqRequest;
-    type Error = io::Error;
+/// Client hello data
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Hello {
+    /// Client protocol version
+    pub version: u8,
+    /// Client supported capabilities
+    pub capabilities: Vec<Capability>,
+    /// Client supported wire formats in order of preference
+    pub formats: Vec<WireFormat>,
+}
 
-    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        let size = {
-            if src.len() < 2 {
-                return Ok(None);
-            }
-            BigEndian::read_u16(src.as_ref()) as usize
-        };
+/// Node hello response data
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HelloAck {
+    /// Node protocol version
+    pub version: u8,
+    /// Capabilities enabled for session
+    pub capabilities: Vec<Capability>,
+    /// Wire format selected for session
+    pub format: WireFormat,
+}
 
-        if src.len() >= size + 2 {
-            src.split_to(2);
-            let buf = src.split_to(size);
-            Ok(Some(json::from_slice::<MqRequest>(&buf)?))
-        } else {
-            Ok(None)
-        }
+/// Register challenge issued by Node.
+/// Client should sign challenge data with SecretKey of
+/// claimed PublicKey to prove key ownership.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RegisterChallenge {
+    /// Node PublicKey
+    pub node: PublicKey,
+    /// Random challenge nonce
+    pub nonce: Vec<u8>,
+}
+
+/// Register challenge sign data domain tag,
+/// challenge signature can't be valid for other signed data
+const REGISTER_CHALLENGE_TAG: &[u8] = b"amq-register-v1";
+
+impl RegisterChallenge {
+    /// Data that should be signed by Client: domain tag,
+    /// node pub_key, nonce and claimed client pub_key
+    pub fn sign_data(&self, pub_key: &PublicKey) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(REGISTER_CHALLENGE_TAG);
+        data.extend_from_slice(&self.node[..]);
+        data.extend_from_slice(&self.nonce);
+        data.extend_from_slice(&pub_key[..]);
+        data
     }
 }
 
-impl Encoder for MqCodec {
-    type Item = MqResponse;
-    type Error = io::Error;
+/// Client presence at Node
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PresenceStatus {
+    pub pub_key: PublicKey,
+    /// Client is registered and connected
+    pub online: bool,
+}
 
-    fn encode(&mut self, msg: MqResponse, dst: &mut BytesMut) -> Result<(), Self::Error> {
-        let msg = json::to_string(&msg).unwrap();
-        let msg_ref: &[u8] = msg.as_ref();
+/// Domain separation tag for box_ PublicKey signing
+const BOX_KEY_TAG: &[u8] = b"amq-box-key-v1";
 
-        dst.reserve(msg_ref.len() + 2);
-        dst.put_u16_be(msg_ref.len() as u16);
-        dst.put(msg_ref);
+/// Client box_ PublicKey for message body encoding.
+/// Key is signed by client SecretKey so peers can check
+/// that it was published by the key owner.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BoxKey {
+    pub key: cipher::PublicKey,
+    pub signature: Signature,
+}
 
-        Ok(())
+impl BoxKey {
+    /// Sign box_ PublicKey with client SecretKey
+    pub fn new(key: cipher::PublicKey, secret_key: &SecretKey) -> Self {
+        let signature = sign::sign(&Self::sign_data(&key), secret_key);
+        BoxKey { key, signature }
+    }
+
+    /// Verify that box_ PublicKey signed by client
+    pub fn verify(&self, pub_key: &PublicKey) -> bool {
+        sign::verify(&self.signature, &Self::sign_data(&self.key), pub_key)
+    }
+
+    fn sign_data(key: &cipher::PublicKey) -> Vec<u8> {
+        let mut data = Vec::from(BOX_KEY_TAG);
+        data.extend_from_slice(&key[..]);
+        data
     }
 }
 
+/// Error reasons for failed requests
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum MqError {
+    /// Register proof sent without challenge
+    ChallengeNotFound,
+    /// Register proof signature is invalid
+    InvalidSignature,
+    /// Client already registered
+    AlreadyRegistered,
+    /// Session not found at MQ server
+    SessionNotFound,
+    /// Request received before Hello
+    HelloRequired,
+    /// Hello received more than once
+    UnexpectedHello,
+    /// Client protocol version not supported, Node version attached
+    UnsupportedVersion(u8),
+    /// Request requires capability not enabled for session
+    CapabilityNotEnabled(Capability),
+    /// Message response sent not by message recipient
+    NotRecipient,
+}
+
+/// Protocol version negotiated with Hello.
+/// Peers with different protocol version can't communicate.
+pub const PROTOCOL_VERSION: u8 = 3;
+
+/// Frame header layout version sent in every frame header.
+/// It's kept stable, so peer with other protocol version
+/// can read Hello and be rejected with clear reason.
+const FRAME_VERSION: u8 = 3;
+
+/// Frame header size: frame version, wire format and `u32` frame length
+const FRAME_HEADER_SIZE: usize = 6;
+
+/// Default max frame size
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+/// Serialization format of frame data.
+/// Every frame header contains format of frame data,
+/// so peers can decode frames before format negotiated.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum WireFormat {
+    #[default]
+    Json,
+    MessagePack,
+    /// Wire format unknown for current peer
+    #[serde(other)]
+    Unknown,
+}
+
+/// Shared wire format of connection. Format can be changed
+/// after negotiation while codec owned by framed writer.
+pub type WireFormatHandle = Rc<Cell<WireFormat>>;
+
+/// Shared bytes counter
+pub type ByteCounter = Rc<Cell<u64>>;
+
+/// Writer counting bytes written to inner writer
+pub struct CountingWrite<W> {
+    inner: W,
+    written: ByteCounter,
+}
+
+impl<W> CountingWrite<W> {
+    pub fn new(inner: W, written: ByteCounter) -> Self {
+        CountingWrite { inner, written }
+    }
+}
+
+impl<W: io::Write> io::Write for CountingWrite<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.written.set(self.written.get() + n as u64);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: AsyncWrite> AsyncWrite for CountingWrite<W> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.inner.shutdown()
+    }
+}
+
+impl WireFormat {
+    /// Is wire format supported by current peer
+    pub fn is_supported(self) -> bool {
+        self != WireFormat::Unknown
+    }
+
+    /// Error for wire format not supported by current peer
+    fn unsupported(self) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Unsupported wire format: {:?}", self),
+        )
+    }
+
+    /// Wire format id for frame header
+    fn id(self) -> Option<u8> {
+        match self {
+            WireFormat::Json => Some(0),
+            WireFormat::MessagePack => Some(1),
+            WireFormat::Unknown => None,
+        }
+    }
+
+    /// Wire format from frame header id
+    fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(WireFormat::Json),
+            1 => Some(WireFormat::MessagePack),
+            _ => None,
+        }
+    }
+
+    /// Serialize frame data
+    pub fn serialize<T: serde::Serialize>(self, item: &T) -> Result<Vec<u8>, io::Error> {
+        match self {
+            WireFormat::Json => json::to_vec(item).map_err(io::Error::from),
+            WireFormat::MessagePack => rmp_serde::to_vec_named(item)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err)),
+            WireFormat::Unknown => Err(self.unsupported()),
+        }
+    }
+
+    /// Deserialize frame data
+    pub fn deserialize<T: serde::de::DeserializeOwned>(self, data: &[u8]) -> Result<T, io::Error> {
+        match self {
+            WireFormat::Json => json::from_slice(data).map_err(io::Error::from),
+            WireFormat::MessagePack => rmp_serde::from_slice(data)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err)),
+            WireFormat::Unknown => Err(self.unsupported()),
+        }
+    }
+}
+
+/// Read frame header, returns frame format and length if header is valid.
+fn read_frame_header(
+    src: &BytesMut,
+    max_frame_size: usize,
+) -> Result<Option<(WireFormat, usize)>, io::Error> {
+    if src.len() < FRAME_HEADER_SIZE {
+        return Ok(None);
+    }
+    if src[0] != FRAME_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Unsupported frame version: {} (expected {})",
+                src[0], FRAME_VERSION
+            ),
+        ));
+    }
+    let format = WireFormat::from_id(src[1]).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Unsupported wire format: {}", src[1]),
+        )
+    })?;
+    let size = BigEndian::read_u32(&src[2..FRAME_HEADER_SIZE]) as usize;
+    if size > max_frame_size {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Frame size {} exceeds max frame size {}",
+                size, max_frame_size
+            ),
+        ));
+    }
+    Ok(Some((format, size)))
+}
+
+/// Write frame header and frame data
+fn write_frame(
+    format: WireFormat,
+    data: &[u8],
+    dst: &mut BytesMut,
+    max_frame_size: usize,
+) -> Result<(), io::Error> {
+    if data.len() > max_frame_size {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "Frame size {} exceeds max frame size {}",
+                data.len(),
+                max_frame_size
+            ),
+        ));
+    }
+
+    let format_id = format.id().ok_or_else(|| format.unsupported())?;
+
+    dst.reserve(data.len() + FRAME_HEADER_SIZE);
+    dst.put_u8(FRAME_VERSION);
+    dst.put_u8(format_id);
+    dst.put_u32_be(data.len() as u32);
+    dst.put(data);
+
+    Ok(())
+}
+
+/// Length-delimited framing codec for `In` incoming
+/// and `Out` outgoing frame data types
+pub struct FramedCodec<In, Out> {
+    /// Max frame size for sending and receiving
+    max_frame_size: usize,
+    /// Wire format for sending
+    format: WireFormatHandle,
+    /// Encoded frames bytes
+    encoded: ByteCounter,
+    items: PhantomData<fn(Out) -> In>,
+}
+
+/// Codec for Client -> Server transport
+pub type MqCodec = FramedCodec<MqRequest, MqResponse>;
+
 /// Codec for Server -> Client transport
-pub struct ClientMqCodec;
+pub type ClientMqCodec = FramedCodec<MqResponse, MqRequest>;
+
+impl<In, Out> FramedCodec<In, Out> {
+    pub fn new(max_frame_size: usize) -> Self {
+        FramedCodec::with_format(max_frame_size, WireFormatHandle::default())
+    }
+
+    /// Codec with shared wire format for sending
+    pub fn with_format(max_frame_size: usize, format: WireFormatHandle) -> Self {
+        FramedCodec {
+            max_frame_size,
+            format,
+            encoded: ByteCounter::default(),
+            items: PhantomData,
+        }
+    }
 
-impl Decoder for ClientMqCodec {
-    type Item = MqResponse;
+    /// Codec with shared counter of encoded frames bytes
+    pub fn with_counter(mut self, encoded: ByteCounter) -> Self {
+        self.encoded = encoded;
+        self
+    }
+}
+
+impl<In, Out> Default for FramedCodec<In, Out> {
+    fn default() -> Self {
+        FramedCodec::new(DEFAULT_MAX_FRAME_SIZE)
+    }
+}
+
+impl<In: serde::de::DeserializeOwned, Out> Decoder for FramedCodec<In, Out> {
+    type Item = In;
     type Error = io::Error;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        let size = {
-            if src.len() < 2 {
-                return Ok(None);
-            }
-            BigEndian::read_u16(src.as_ref()) as usize
+        let (format, size) = match read_frame_header(src, self.max_frame_size)? {
+            Some(header) => header,
+            None => return Ok(None),
         };
 
-        if src.len() >= size + 2 {
-            src.split_to(2);
+        if src.len() >= size + FRAME_HEADER_SIZE {
+            src.split_to(FRAME_HEADER_SIZE);
             let buf = src.split_to(size);
-            Ok(Some(json::from_slice::<MqResponse>(&buf)?))
+            Ok(Some(format.deserialize(&buf)?))
         } else {
             Ok(None)
         }
     }
 }
 
-impl Encoder for ClientMqCodec {
-    type Item = MqRequest;
+impl<In, Out: serde::Serialize> Encoder for FramedCodec<In, Out> {
+    type Item = Out;
     type Error = io::Error;
 
-    fn encode(&mut self, msg: MqRequest, dst: &mut BytesMut) -> Result<(), Self::Error> {
-        let msg = json::to_string(&msg).unwrap();
-        let msg_ref: &[u8] = msg.as_ref();
+    fn encode(&mut self, msg: Out, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let format = self.format.get();
+        let data = format.serialize(&msg)?;
+        write_frame(format, &data, dst, self.max_frame_size)?;
+        self.encoded
+            .set(self.encoded.get() + (FRAME_HEADER_SIZE + data.len()) as u64);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        dst.reserve(msg_ref.len() + 2);
-        dst.put_u16_be(msg_ref.len() as u16);
-        dst.put(msg_ref);
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Frame {
+        name: String,
+        body: MessageBody,
+    }
 
-        Ok(())
+    type FrameCodec = FramedCodec<Frame, Frame>;
+
+    fn frame(body: &[u8]) -> Frame {
+        Frame {
+            name: "frame".to_owned(),
+            body: MessageBody(body.to_vec()),
+        }
+    }
+
+    /// Frame header with announced data length
+    fn header(version: u8, format: u8, size: u32) -> BytesMut {
+        let mut buf = BytesMut::with_capacity(FRAME_HEADER_SIZE);
+        buf.put_u8(version);
+        buf.put_u8(format);
+        buf.put_u32_be(size);
+        buf
+    }
+
+    #[test]
+    fn frame_round_trip_in_all_formats() {
+        for format in [WireFormat::Json, WireFormat::MessagePack] {
+            let mut codec = FrameCodec::with_format(1024, Rc::new(Cell::new(format)));
+            let mut buf = BytesMut::new();
+            codec.encode(frame(b"text"), &mut buf).unwrap();
+            codec.encode(frame(&[0, 159, 146, 150]), &mut buf).unwrap();
+
+            assert_eq!(codec.decode(&mut buf).unwrap(), Some(frame(b"text")));
+            assert_eq!(
+                codec.decode(&mut buf).unwrap(),
+                Some(frame(&[0, 159, 146, 150]))
+            );
+            assert_eq!(codec.decode(&mut buf).unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn partial_frame_is_not_decoded() {
+        let mut codec = FrameCodec::default();
+        let mut encoded = BytesMut::new();
+        codec.encode(frame(b"text"), &mut encoded).unwrap();
+
+        // Partial header
+        let mut buf = BytesMut::from(&encoded[..FRAME_HEADER_SIZE - 1]);
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+        // Partial body
+        let mut buf = BytesMut::from(&encoded[..encoded.len() - 1]);
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+        assert_eq!(buf.len(), encoded.len() - 1);
+
+        buf.extend_from_slice(&encoded[encoded.len() - 1..]);
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(frame(b"text")));
+    }
+
+    #[test]
+    fn oversized_frame_is_rejected() {
+        let mut codec = FrameCodec::new(16);
+        let mut buf = header(FRAME_VERSION, 0, 17);
+        assert!(codec.decode(&mut buf).is_err());
+
+        let mut buf = BytesMut::new();
+        assert!(codec.encode(frame(&[0; 16]), &mut buf).is_err());
+    }
+
+    #[test]
+    fn unknown_frame_header_is_rejected() {
+        let mut codec = FrameCodec::default();
+        let mut buf = header(FRAME_VERSION + 1, 0, 0);
+        assert!(codec.decode(&mut buf).is_err());
+        let mut buf = header(FRAME_VERSION, 2, 0);
+        assert!(codec.decode(&mut buf).is_err());
+
+        let format = Rc::new(Cell::new(WireFormat::Unknown));
+        let mut codec = FrameCodec::with_format(1024, format);
+        assert!(codec.encode(frame(b"text"), &mut BytesMut::new()).is_err());
+    }
+
+    #[test]
+    fn json_body_forms() {
+        let text: MessageBody = json::from_str(r#""text""#).unwrap();
+        assert_eq!(text, MessageBody::from("text"));
+        assert_eq!(json::to_string(&text).unwrap(), r#""text""#);
+
+        let binary = MessageBody(vec![0, 159, 146, 150]);
+        let encoded = json::to_string(&binary).unwrap();
+        assert_eq!(encoded, r#"{"base64":"AJ+Slg=="}"#);
+        assert_eq!(json::from_str::<MessageBody>(&encoded).unwrap(), binary);
+
+        assert!(json::from_str::<MessageBody>(r#"{"hex":"00"}"#).is_err());
+        assert!(json::from_str::<MessageBody>(r#"{"base64":"!"}"#).is_err());
     }
 }
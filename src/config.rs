@@ -1,8 +1,13 @@
-use crate::types::{ClientConfig, ClientMessageConfig, ClientNodeConfig, NodeConfig};
+use crate::codec::{WireFormat, DEFAULT_MAX_FRAME_SIZE};
+use crate::types::{
+    ClientConfig, ClientMessageConfig, ClientNodeConfig, DedupConfig, HistoryConfig, MailboxConfig,
+    NodeConfig, OutboundConfig, RateLimitConfig, SignaturePolicy,
+};
 use std::env;
 use std::fs;
 use toml;
 
+mod codec;
 mod sign;
 mod types;
 
@@ -25,6 +30,8 @@ fn generate_config_date(config_type: AppConfigType) -> String {
                 node: ClientNodeConfig {
                     ip: "0.0.0.0".to_string(),
                     port: 3030,
+                    max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+                    wire_format: WireFormat::Json,
                 },
                 message: ClientMessageConfig {
                     public_key: sign::to_hex(&box_pk[..]),
@@ -40,6 +47,16 @@ fn generate_config_date(config_type: AppConfigType) -> String {
                 public_key: sign::to_hex_pk(&pk),
                 secret_key: sign::to_hex_sk(&sk),
                 port: 3030,
+                signature_policy: SignaturePolicy::VerifyIfPresent,
+                max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+                request_timeout_sec: 30,
+                ack_timeout_sec: 10,
+                mailbox: MailboxConfig::default(),
+                history: HistoryConfig::default(),
+                dedup: DedupConfig::default(),
+                outbound: OutboundConfig::default(),
+                rate_limit: RateLimitConfig::default(),
+                wal: None,
             };
             toml::to_string_pretty(&cfg).unwrap()
         }
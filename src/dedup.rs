@@ -0,0 +1,108 @@
+use sodiumoxide::crypto::sign::ed25519::PublicKey;
+use std::collections::{HashSet, VecDeque};
+use std::time::{Duration, Instant};
+
+use crate::types::DedupConfig;
+
+/// Message key: sender and message id
+type MessageKey = (PublicKey, String);
+
+/// Accepted messages window for duplicates detection.
+/// Message is duplicate if message with same sender and id
+/// was accepted during window time.
+#[derive(Debug, Default)]
+pub struct DedupWindow {
+    keys: HashSet<MessageKey>,
+    /// Keys in accepted order with accepted time
+    order: VecDeque<(Instant, MessageKey)>,
+}
+
+impl DedupWindow {
+    /// Check is message already accepted during window
+    pub fn contains(&mut self, from: &PublicKey, id: &str, cfg: &DedupConfig) -> bool {
+        self.purge_expired(cfg);
+        self.keys.contains(&(*from, id.to_owned()))
+    }
+
+    /// Remember accepted message
+    pub fn insert(&mut self, from: PublicKey, id: String, cfg: &DedupConfig) {
+        if cfg.window_sec == 0 {
+            return;
+        }
+        let key = (from, id);
+        if !self.keys.insert(key.clone()) {
+            return;
+        }
+        self.order.push_back((Instant::now(), key));
+        // Oldest keys are removed when capacity exceeded
+        while self.order.len() > cfg.capacity {
+            if let Some((_, key)) = self.order.pop_front() {
+                self.keys.remove(&key);
+            }
+        }
+    }
+
+    /// Remove keys accepted before window
+    fn purge_expired(&mut self, cfg: &DedupConfig) {
+        let window = Duration::from_secs(cfg.window_sec);
+        let now = Instant::now();
+        while let Some((accepted_at, _)) = self.order.front() {
+            if now.duration_since(*accepted_at) <= window {
+                break;
+            }
+            if let Some((_, key)) = self.order.pop_front() {
+                self.keys.remove(&key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(window_sec: u64, capacity: usize) -> DedupConfig {
+        DedupConfig {
+            window_sec,
+            capacity,
+        }
+    }
+
+    #[test]
+    fn accepted_key_expires_after_window() {
+        let cfg = config(60, 10);
+        let from = PublicKey([1; 32]);
+        let mut window = DedupWindow::default();
+        window.insert(from, "a".to_owned(), &cfg);
+        window.insert(from, "b".to_owned(), &cfg);
+        assert!(window.contains(&from, "a", &cfg));
+        assert!(!window.contains(&PublicKey([2; 32]), "a", &cfg));
+
+        // First key accepted before window
+        window.order[0].0 = Instant::now() - Duration::from_secs(61);
+        assert!(!window.contains(&from, "a", &cfg));
+        assert!(window.contains(&from, "b", &cfg));
+    }
+
+    #[test]
+    fn oldest_key_evicted_by_capacity() {
+        let cfg = config(60, 2);
+        let from = PublicKey([1; 32]);
+        let mut window = DedupWindow::default();
+        for id in ["a", "b", "c"] {
+            window.insert(from, id.to_owned(), &cfg);
+        }
+        assert!(!window.contains(&from, "a", &cfg));
+        assert!(window.contains(&from, "b", &cfg));
+        assert!(window.contains(&from, "c", &cfg));
+    }
+
+    #[test]
+    fn zero_window_disables_detection() {
+        let cfg = config(0, 10);
+        let from = PublicKey([1; 32]);
+        let mut window = DedupWindow::default();
+        window.insert(from, "a".to_owned(), &cfg);
+        assert!(!window.contains(&from, "a", &cfg));
+    }
+}
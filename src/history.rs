@@ -0,0 +1,250 @@
+use sodiumoxide::crypto::sign::ed25519::PublicKey;
+use std::collections::{HashMap, VecDeque};
+
+use crate::server::MqMessage;
+
+/// Retained PUB messages of event for durable subscribers.
+///
+/// Every retained message has sequence number, and every durable
+/// subscriber has position - sequence number of last delivered
+/// message. Messages delivered to all durable subscribers are removed.
+#[derive(Debug, Default)]
+pub struct EventHistory {
+    /// Sequence number of last published message
+    last_seq: u64,
+    /// Retained messages in published order
+    messages: VecDeque<(u64, MqMessage)>,
+    /// Durable subscribers positions
+    positions: HashMap<PublicKey, u64>,
+}
+
+impl EventHistory {
+    /// Add durable subscriber. New subscriber starts
+    /// from messages published after subscription.
+    pub fn subscribe(&mut self, pub_key: PublicKey) {
+        let last_seq = self.last_seq;
+        self.positions.entry(pub_key).or_insert(last_seq);
+    }
+
+    /// Remove durable subscriber
+    pub fn unsubscribe(&mut self, pub_key: &PublicKey) {
+        self.positions.remove(pub_key);
+        self.trim();
+    }
+
+    /// Check is client durable subscriber
+    pub fn is_subscribed(&self, pub_key: &PublicKey) -> bool {
+        self.positions.contains_key(pub_key)
+    }
+
+    /// Durable subscribers positions
+    pub fn positions(&self) -> impl Iterator<Item = (&PublicKey, &u64)> {
+        self.positions.iter()
+    }
+
+    /// Retain published message, returns message sequence number
+    /// and oldest messages removed when capacity exceeded.
+    pub fn push(&mut self, message: MqMessage, capacity: usize) -> (u64, Vec<(u64, MqMessage)>) {
+        self.last_seq += 1;
+        self.restore(self.last_seq, message);
+        let mut removed = vec![];
+        while self.messages.len() > capacity {
+            removed.extend(self.messages.pop_front());
+        }
+        (self.last_seq, removed)
+    }
+
+    /// Durable subscribers not received message yet
+    pub fn lagging(&self, seq: u64) -> impl Iterator<Item = &PublicKey> {
+        self.positions
+            .iter()
+            .filter(move |(_, position)| **position < seq)
+            .map(|(pub_key, _)| pub_key)
+    }
+
+    /// Retain message with known sequence number,
+    /// used for history restoring.
+    pub fn restore(&mut self, seq: u64, message: MqMessage) {
+        self.last_seq = self.last_seq.max(seq);
+        self.messages.push_back((seq, message));
+    }
+
+    /// Messages not delivered to subscriber yet
+    pub fn pending(&self, pub_key: &PublicKey) -> Vec<(u64, MqMessage)> {
+        match self.positions.get(pub_key) {
+            Some(position) => self
+                .messages
+                .iter()
+                .filter(|(seq, _)| seq > position)
+                .cloned()
+                .collect(),
+            None => vec![],
+        }
+    }
+
+    /// Retained messages in published order
+    pub fn messages(&self) -> impl Iterator<Item = &(u64, MqMessage)> {
+        self.messages.iter()
+    }
+
+    /// Set durable subscriber position to delivered message.
+    /// Returns false if client is not durable subscriber.
+    pub fn delivered(&mut self, pub_key: &PublicKey, seq: u64) -> bool {
+        // Sequence numbers should not be reused after
+        // all retained messages removed
+        self.last_seq = self.last_seq.max(seq);
+        match self.positions.get_mut(pub_key) {
+            Some(position) => *position = seq,
+            None => return false,
+        }
+        self.trim();
+        true
+    }
+
+    /// Check is there any durable subscriber
+    pub fn is_empty(&self) -> bool {
+        self.positions.is_empty()
+    }
+
+    /// Remove messages delivered to all durable subscribers
+    fn trim(&mut self) {
+        let min = self
+            .positions
+            .values()
+            .min()
+            .cloned()
+            .unwrap_or(self.last_seq);
+        while let Some((seq, _)) = self.messages.front() {
+            if *seq > min {
+                break;
+            }
+            self.messages.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::MessageProtocol;
+    use std::time::SystemTime;
+
+    fn message(id: &str) -> MqMessage {
+        MqMessage {
+            id: id.to_owned(),
+            from: PublicKey([0; 32]),
+            to: None,
+            signature: None,
+            event: Some("event".to_owned()),
+            protocol: MessageProtocol::Pub { retain: false },
+            time: SystemTime::now(),
+            nonce: None,
+            body: "{}".into(),
+            content_type: None,
+            expires_at: None,
+            deliver_at: None,
+            redelivered: 0,
+        }
+    }
+
+    /// Ids of subscriber pending messages
+    fn pending(history: &EventHistory, pub_key: &PublicKey) -> Vec<String> {
+        history
+            .pending(pub_key)
+            .into_iter()
+            .map(|(_, message)| message.id)
+            .collect()
+    }
+
+    #[test]
+    fn push_drops_oldest_over_capacity() {
+        let subscriber = PublicKey([1; 32]);
+        let mut history = EventHistory::default();
+        history.subscribe(subscriber);
+
+        assert_eq!(history.push(message("1"), 2).0, 1);
+        assert_eq!(history.push(message("2"), 2).0, 2);
+        let (seq, removed) = history.push(message("3"), 2);
+        assert_eq!(seq, 3);
+        assert_eq!(removed.len(), 1);
+        assert_eq!(removed[0].0, 1);
+        assert_eq!(removed[0].1.id, "1");
+        assert_eq!(pending(&history, &subscriber), vec!["2", "3"]);
+    }
+
+    #[test]
+    fn subscriber_starts_after_last_message() {
+        let (old, new) = (PublicKey([1; 32]), PublicKey([2; 32]));
+        let mut history = EventHistory::default();
+        history.subscribe(old);
+        history.push(message("1"), 10);
+        history.subscribe(new);
+        history.push(message("2"), 10);
+
+        assert_eq!(pending(&history, &old), vec!["1", "2"]);
+        assert_eq!(pending(&history, &new), vec!["2"]);
+        assert!(pending(&history, &PublicKey([3; 32])).is_empty());
+    }
+
+    #[test]
+    fn delivered_trims_messages_of_all_subscribers() {
+        let (first, second) = (PublicKey([1; 32]), PublicKey([2; 32]));
+        let mut history = EventHistory::default();
+        history.subscribe(first);
+        history.subscribe(second);
+        history.push(message("1"), 10);
+        history.push(message("2"), 10);
+
+        assert!(history.delivered(&first, 2));
+        assert_eq!(history.messages().count(), 2);
+        assert!(history.delivered(&second, 1));
+        assert_eq!(history.messages().count(), 1);
+        assert!(!history.delivered(&PublicKey([3; 32]), 2));
+
+        // Unsubscribed subscriber doesn't hold messages
+        history.unsubscribe(&second);
+        assert_eq!(history.messages().count(), 0);
+        assert!(!history.is_empty());
+        history.unsubscribe(&first);
+        assert!(history.is_empty());
+    }
+
+    #[test]
+    fn lagging_subscribers_of_message() {
+        let (first, second) = (PublicKey([1; 32]), PublicKey([2; 32]));
+        let mut history = EventHistory::default();
+        history.subscribe(first);
+        history.subscribe(second);
+        history.push(message("1"), 10);
+        history.push(message("2"), 10);
+        history.delivered(&first, 1);
+
+        let mut lagging: Vec<PublicKey> = history.lagging(1).cloned().collect();
+        lagging.sort();
+        assert_eq!(lagging, vec![second]);
+        let mut lagging: Vec<PublicKey> = history.lagging(2).cloned().collect();
+        lagging.sort();
+        assert_eq!(lagging, vec![first, second]);
+    }
+
+    #[test]
+    fn restore_keeps_sequence_numbers() {
+        let subscriber = PublicKey([1; 32]);
+        let mut history = EventHistory::default();
+        history.restore(5, message("5"));
+        history.restore(6, message("6"));
+        history.subscribe(subscriber);
+        history.delivered(&subscriber, 5);
+
+        assert_eq!(pending(&history, &subscriber), vec!["6"]);
+        // New messages continue after restored ones
+        assert_eq!(history.push(message("7"), 10).0, 7);
+
+        // Sequence numbers are not reused after all messages delivered
+        let mut history = EventHistory::default();
+        history.subscribe(subscriber);
+        history.delivered(&subscriber, 9);
+        assert_eq!(history.messages().count(), 0);
+        assert_eq!(history.push(message("10"), 10).0, 10);
+    }
+}
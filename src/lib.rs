@@ -1,7 +1,15 @@
 pub mod client;
 pub mod codec;
+pub mod dedup;
+pub mod history;
+pub mod mailbox;
 pub mod node;
+pub mod presence;
+pub mod ratelimit;
 pub mod server;
 pub mod session;
 pub mod sign;
+pub mod timer;
+pub mod topic;
 pub mod types;
+pub mod wal;
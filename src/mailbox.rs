@@ -0,0 +1,74 @@
+use serde_derive::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::time::{Duration, SystemTime};
+
+use crate::server::MqMessage;
+use crate::types::MailboxConfig;
+
+/// Message queued for offline recipient
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QueuedMessage {
+    pub queued_at: SystemTime,
+    pub message: MqMessage,
+}
+
+/// Offline recipient mailbox. Messages are stored
+/// in order and delivered when recipient registered.
+#[derive(Debug, Default)]
+pub struct Mailbox {
+    messages: VecDeque<QueuedMessage>,
+}
+
+impl Mailbox {
+    /// Queue message for recipient. Returns false
+    /// if mailbox is full and message is not queued.
+    pub fn push_queued(&mut self, queued: QueuedMessage, cfg: &MailboxConfig) -> bool {
+        self.purge_expired(cfg);
+        if self.messages.len() >= cfg.capacity {
+            return false;
+        }
+        self.messages.push_back(queued);
+        true
+    }
+
+    /// Remove messages which time to live or
+    /// message expiration time passed
+    pub fn purge_expired(&mut self, cfg: &MailboxConfig) {
+        let ttl = Duration::from_secs(cfg.ttl_sec);
+        let now = SystemTime::now();
+        // Messages queued in order, so expired
+        // messages are always at the front
+        while let Some(queued) = self.messages.front() {
+            match now.duration_since(queued.queued_at) {
+                Ok(age) if age > ttl => {
+                    self.messages.pop_front();
+                }
+                _ => break,
+            }
+        }
+        self.messages
+            .retain(|queued| !queued.message.is_expired(now));
+    }
+
+    /// Take all not expired messages in queued order
+    pub fn drain(&mut self, cfg: &MailboxConfig) -> Vec<MqMessage> {
+        self.purge_expired(cfg);
+        self.messages
+            .drain(..)
+            .map(|queued| queued.message)
+            .collect()
+    }
+
+    /// Queued messages in queued order
+    pub fn iter(&self) -> impl Iterator<Item = &QueuedMessage> {
+        self.messages.iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+}
@@ -8,15 +8,17 @@ use tokio_codec::FramedRead;
 use tokio_io::AsyncRead;
 use tokio_tcp::{TcpListener, TcpStream};
 
-use crate::codec::MqCodec;
+use crate::codec::{ByteCounter, CountingWrite, MqCodec, WireFormatHandle};
 use crate::server::MqServer;
 use crate::session::MqSession;
 use crate::types::{NodeAppConfig, NodeConfig};
+use crate::wal::Wal;
 
 /// Define tcp server that will accept incoming tcp
 /// connection and create MQ actors.
 struct Server {
     server: Addr<MqServer>,
+    config: NodeAppConfig,
 }
 
 /// Make actor from `Server`
@@ -39,10 +41,24 @@ impl Handler<TcpConnect> for Server {
         // For each incoming connection we create `MqSession` actor
         // with out MQ server address.
         let server = self.server.clone();
+        let config = self.config.clone();
         MqSession::create(move |ctx| {
             let (r, w) = msg.0.split();
-            MqSession::add_stream(FramedRead::new(r, MqCodec), ctx);
-            MqSession::new(server, FramedWrite::new(w, MqCodec, ctx))
+            let max_frame_size = config.max_frame_size;
+            let format = WireFormatHandle::default();
+            // Counters of bytes written to and sent from write buffer
+            let (encoded, written) = (ByteCounter::default(), ByteCounter::default());
+            let codec =
+                MqCodec::with_format(max_frame_size, format.clone()).with_counter(encoded.clone());
+            MqSession::add_stream(FramedRead::new(r, MqCodec::new(max_frame_size)), ctx);
+            MqSession::new(
+                server,
+                FramedWrite::new(CountingWrite::new(w, written.clone()), codec, ctx),
+                config,
+                format,
+                encoded,
+                written,
+            )
         });
     }
 }
@@ -65,8 +81,17 @@ impl MqNode {
     pub fn serve(&self) {
         let config = self.config.clone();
         actix::System::run(move || {
+            // Restore server state from write-ahead log
+            let mut server = MqServer::new(config.clone());
+            if let Some(ref wal_config) = config.wal {
+                let (wal, records) = Wal::open(wal_config).expect("Can't open WAL");
+                server
+                    .restore(wal, records)
+                    .expect("Can't restore from WAL");
+            }
+
             // Start server actor
-            let server = MqServer::new(config.clone()).start();
+            let server = server.start();
 
             // Create server listener
             let addr = net::SocketAddr::from_str(&format!("0.0.0.0:{:?}", config.port))
@@ -83,7 +108,7 @@ impl MqNode {
                     let addr = stream.peer_addr().unwrap();
                     TcpConnect(stream, addr)
                 }));
-                Server { server }
+                Server { server, config }
             });
 
             info!("Running MQ server...");
@@ -0,0 +1,56 @@
+use sodiumoxide::crypto::sign::ed25519::PublicKey;
+use std::collections::{HashMap, HashSet};
+
+/// Presence watchers of clients.
+///
+/// Watcher is notified when watched client
+/// goes online (registered) or offline (disconnected).
+#[derive(Debug, Default)]
+pub struct PresenceWatchers {
+    /// Watchers by watched client
+    watchers: HashMap<PublicKey, HashSet<PublicKey>>,
+    /// Watched clients by watcher
+    watched: HashMap<PublicKey, HashSet<PublicKey>>,
+}
+
+impl PresenceWatchers {
+    /// Watch clients presence changes
+    pub fn watch(&mut self, watcher: PublicKey, keys: &[PublicKey]) {
+        for key in keys {
+            self.watchers.entry(*key).or_default().insert(watcher);
+            self.watched.entry(watcher).or_default().insert(*key);
+        }
+    }
+
+    /// Stop watching clients presence changes
+    pub fn unwatch(&mut self, watcher: &PublicKey, keys: &[PublicKey]) {
+        for key in keys {
+            if let Some(watchers) = self.watchers.get_mut(key) {
+                watchers.remove(watcher);
+                if watchers.is_empty() {
+                    self.watchers.remove(key);
+                }
+            }
+            if let Some(watched) = self.watched.get_mut(watcher) {
+                watched.remove(key);
+                if watched.is_empty() {
+                    self.watched.remove(watcher);
+                }
+            }
+        }
+    }
+
+    /// Remove all watches of watcher
+    pub fn remove_watcher(&mut self, watcher: &PublicKey) {
+        let keys: Vec<PublicKey> = match self.watched.get(watcher) {
+            Some(watched) => watched.iter().cloned().collect(),
+            None => return,
+        };
+        self.unwatch(watcher, &keys);
+    }
+
+    /// Watchers of client presence
+    pub fn watchers(&self, pub_key: &PublicKey) -> impl Iterator<Item = &PublicKey> {
+        self.watchers.get(pub_key).into_iter().flatten()
+    }
+}
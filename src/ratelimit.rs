@@ -0,0 +1,85 @@
+use std::time::Instant;
+
+use crate::types::RateLimit;
+
+/// Token bucket refilled continuously with `rate` tokens
+/// per second up to `rate` tokens. Zero rate disables limit.
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u64) -> TokenBucket {
+        TokenBucket {
+            rate: rate as f64,
+            tokens: rate as f64,
+            updated: Instant::now(),
+        }
+    }
+
+    /// Check is there enough tokens, tokens are not taken
+    pub fn has(&mut self, amount: u64, now: Instant) -> bool {
+        if self.rate == 0.0 {
+            return true;
+        }
+        self.tokens = self.available(now);
+        self.updated = now;
+        self.tokens >= amount as f64
+    }
+
+    /// Is bucket refilled, full bucket is same as new one
+    pub fn is_full(&self, now: Instant) -> bool {
+        self.available(now) >= self.rate
+    }
+
+    /// Tokens available at time
+    fn available(&self, now: Instant) -> f64 {
+        let elapsed = now.duration_since(self.updated);
+        (self.tokens + elapsed.as_secs_f64() * self.rate).min(self.rate)
+    }
+
+    /// Take tokens
+    pub fn take(&mut self, amount: u64) {
+        if self.rate != 0.0 {
+            self.tokens -= amount as f64;
+        }
+    }
+}
+
+/// Client requests rate limiter by requests count
+/// and message body bytes per second. Limiter is kept
+/// by MQ server between client sessions.
+#[derive(Debug)]
+pub struct RateLimiter {
+    messages: TokenBucket,
+    bytes: TokenBucket,
+}
+
+impl RateLimiter {
+    pub fn new(limit: &RateLimit) -> RateLimiter {
+        RateLimiter {
+            messages: TokenBucket::new(limit.messages_per_sec),
+            bytes: TokenBucket::new(limit.bytes_per_sec),
+        }
+    }
+
+    /// Take tokens for request with message body size.
+    /// Returns false if rate limit exceeded.
+    pub fn check(&mut self, bytes: u64) -> bool {
+        let now = Instant::now();
+        if !self.messages.has(1, now) || !self.bytes.has(bytes, now) {
+            return false;
+        }
+        self.messages.take(1);
+        self.bytes.take(bytes);
+        true
+    }
+
+    /// Are all tokens refilled since last request
+    pub fn is_full(&self, now: Instant) -> bool {
+        self.messages.is_full(now) && self.bytes.is_full(now)
+    }
+}
@@ -1,17 +1,38 @@
-use crate::types::NodeAppConfig;
+use crate::types::{NodeAppConfig, SignaturePolicy};
 use actix::prelude::*;
 use actix::Message;
 use serde_derive::{Deserialize, Serialize};
-use serde_json as json;
 use sodiumoxide::crypto::box_ as cipher;
 use sodiumoxide::crypto::sign::ed25519::{PublicKey, Signature};
-use std::collections::HashMap;
-use std::time::SystemTime;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::time::{Duration, Instant, SystemTime};
 
 use crate::codec;
-use crate::codec::MessageProtocol::{Pub, Sub, UnSub};
+use crate::codec::MessageProtocol::{Pub, Reply, ReqRep, Sub, UnSub};
+use crate::dedup::DedupWindow;
+use crate::history::EventHistory;
+use crate::mailbox::{Mailbox, QueuedMessage};
+use crate::presence::PresenceWatchers;
+use crate::ratelimit::RateLimiter;
 use crate::session;
 use crate::sign;
+use crate::timer::TimerWheel;
+use crate::topic::{self, TopicTrie};
+use crate::types::FsyncPolicy;
+use crate::wal::{Wal, WalRecord};
+
+/// Mailboxes expired messages purge interval
+const MAILBOX_PURGE_SEC: u64 = 60;
+/// Write-ahead log sync interval for `EverySecond` policy
+const WAL_SYNC_SEC: u64 = 1;
+/// Write-ahead log size check interval for checkpoint
+const WAL_CHECKPOINT_SEC: u64 = 10;
+/// Scheduled messages timer wheel tick
+const SCHEDULE_TICK_MS: u64 = 100;
+/// Scheduled messages timer wheel slots,
+/// wheel rotation is about 100 seconds
+const SCHEDULE_SLOTS: usize = 1024;
 
 /// `MqServer` manages MQ network and
 /// responsible for network nodes
@@ -19,21 +40,794 @@ use crate::sign;
 #[allow(dead_code)]
 pub struct MqServer {
     sessions: HashMap<PublicKey, Addr<session::MqSession>>,
+    /// Clients box_ PublicKey directory
+    keys: HashMap<PublicKey, codec::BoxKey>,
     events: MessageEvents,
+    /// Clients registered at least once
+    known: HashSet<PublicKey>,
+    /// Queued messages for offline clients
+    mailboxes: HashMap<PublicKey, Mailbox>,
+    /// Messages handed to online sessions and not done yet,
+    /// by recipient and by sender and message id
+    unacked: HashMap<PublicKey, HashMap<(PublicKey, String), MqMessage>>,
+    /// Node state write-ahead log
+    wal: Option<Wal>,
+    /// REQ/REP requests waiting for reply
+    /// by requester and request id
+    requests: HashMap<(PublicKey, String), PendingRequest>,
+    /// Recently accepted messages
+    accepted: DedupWindow,
+    /// Messages held until delivery time
+    /// by sender and message id
+    scheduled: HashMap<(PublicKey, String), MqMessage>,
+    /// Scheduled messages timers
+    timers: TimerWheel<(PublicKey, String)>,
+    /// Last-will messages of connected clients
+    wills: HashMap<PublicKey, MqMessage>,
+    /// Clients presence watchers
+    presence: PresenceWatchers,
+    /// Rate limiters of disconnected clients,
+    /// limiter is moved to session when registered
+    limiters: HashMap<PublicKey, RateLimiter>,
     settigns: NodeAppConfig,
 }
 
+/// REQ/REP request waiting for reply
+struct PendingRequest {
+    /// Request recipient
+    responder: PublicKey,
+    /// Request timeout timer
+    timeout: SpawnHandle,
+}
+
 #[allow(dead_code)]
 impl MqServer {
     pub fn new(cfg: NodeAppConfig) -> MqServer {
         MqServer {
             sessions: HashMap::new(),
+            keys: HashMap::new(),
             events: MessageEvents {
-                subscribers: HashMap::new(),
+                subscribers: TopicTrie::default(),
+                durable: HashMap::new(),
+                subscriptions: HashMap::new(),
+                retained: HashMap::new(),
             },
+            known: HashSet::new(),
+            mailboxes: HashMap::new(),
+            unacked: HashMap::new(),
+            wal: None,
+            requests: HashMap::new(),
+            accepted: DedupWindow::default(),
+            scheduled: HashMap::new(),
+            timers: TimerWheel::new(Duration::from_millis(SCHEDULE_TICK_MS), SCHEDULE_SLOTS),
+            wills: HashMap::new(),
+            presence: PresenceWatchers::default(),
+            limiters: HashMap::new(),
             settigns: cfg,
         }
     }
+
+    /// Restore Node state from write-ahead log records
+    /// and continue logging state changes to it.
+    pub fn restore(&mut self, mut wal: Wal, records: Vec<WalRecord>) -> io::Result<()> {
+        println!("Restore from WAL: {} records", records.len());
+        for record in records {
+            self.apply(record);
+        }
+
+        // Messages not acknowledged before restart are
+        // redelivered when recipient registered
+        for (to, messages) in self.unacked.drain() {
+            let mailbox = self.mailboxes.entry(to).or_default();
+            for (_, mut message) in messages {
+                message.redelivered += 1;
+                let queued = QueuedMessage {
+                    queued_at: SystemTime::now(),
+                    message,
+                };
+                if !mailbox.push_queued(queued, &self.settigns.mailbox) {
+                    eprintln!("Restore unacked message failed: mailbox full");
+                }
+            }
+        }
+        self.mailboxes.retain(|_, mailbox| !mailbox.is_empty());
+
+        // Sessions are not restored, so not durable subscriptions
+        // of clients connected before restart are removed
+        let subscribers: Vec<PublicKey> = self.events.subscriptions.keys().cloned().collect();
+        for pub_key in subscribers.iter() {
+            self.remove_subscriptions(pub_key);
+        }
+
+        // Compact log to current state
+        wal.checkpoint(&self.state())?;
+
+        self.wal = Some(wal);
+        Ok(())
+    }
+
+    /// Write-ahead log records of current Node state
+    fn state(&self) -> Vec<WalRecord> {
+        let mut state = vec![];
+        for pub_key in self.known.iter() {
+            state.push(WalRecord::Registered(*pub_key));
+        }
+        for (pub_key, key) in self.keys.iter() {
+            state.push(WalRecord::KeyPublished {
+                pub_key: *pub_key,
+                key: key.clone(),
+            });
+        }
+        for (pub_key, events) in self.events.subscriptions.iter() {
+            for (event, group) in events {
+                let history = self.events.durable.get(event);
+                state.push(WalRecord::Subscribed {
+                    event: event.clone(),
+                    pub_key: *pub_key,
+                    durable: history.is_some_and(|h| h.is_subscribed(pub_key)),
+                    group: group.clone(),
+                });
+            }
+        }
+        for (event, message) in self.events.retained.iter() {
+            state.push(WalRecord::Retained {
+                event: event.clone(),
+                message: Box::new(message.clone()),
+            });
+        }
+        for (event, history) in self.events.durable.iter() {
+            for (seq, message) in history.messages() {
+                state.push(WalRecord::Published {
+                    event: event.clone(),
+                    seq: *seq,
+                    message: Box::new(message.clone()),
+                });
+            }
+            for (pub_key, seq) in history.positions() {
+                state.push(WalRecord::Delivered {
+                    event: event.clone(),
+                    pub_key: *pub_key,
+                    seq: *seq,
+                });
+            }
+        }
+        for (to, mailbox) in self.mailboxes.iter() {
+            for queued in mailbox.iter() {
+                state.push(WalRecord::Queued {
+                    to: *to,
+                    queued: Box::new(queued.clone()),
+                });
+            }
+        }
+        for (to, messages) in self.unacked.iter() {
+            for message in messages.values() {
+                state.push(WalRecord::Sent {
+                    to: *to,
+                    message: Box::new(message.clone()),
+                });
+            }
+        }
+        for message in self.scheduled.values() {
+            state.push(WalRecord::Scheduled(Box::new(message.clone())));
+        }
+        state
+    }
+
+    /// Compact write-ahead log to current Node state
+    fn checkpoint(&mut self) {
+        let state = self.state();
+        if let Some(wal) = self.wal.as_mut() {
+            println!("WAL checkpoint: {} records", state.len());
+            if let Err(err) = wal.checkpoint(&state) {
+                eprintln!("WAL checkpoint error: {}", err);
+            }
+        }
+    }
+
+    /// Apply write-ahead log record to Node state
+    fn apply(&mut self, record: WalRecord) {
+        match record {
+            WalRecord::Registered(pub_key) => {
+                self.known.insert(pub_key);
+            }
+            WalRecord::KeyPublished { pub_key, key } => {
+                self.keys.insert(pub_key, key);
+            }
+            WalRecord::Subscribed {
+                event,
+                pub_key,
+                durable,
+                group,
+            } => self.subscribe(event, pub_key, durable, group),
+            WalRecord::Unsubscribed { event, pub_key } => self.unsubscribe(&event, &pub_key),
+            WalRecord::Retained { event, message } => {
+                self.events.retained.insert(event, *message);
+            }
+            WalRecord::Unretained { event } => {
+                self.events.retained.remove(&event);
+            }
+            WalRecord::Published {
+                event,
+                seq,
+                message,
+            } => {
+                let history = self.events.durable.entry(event).or_default();
+                history.restore(seq, *message);
+            }
+            WalRecord::Delivered {
+                event,
+                pub_key,
+                seq,
+            } => {
+                if let Some(history) = self.events.durable.get_mut(&event) {
+                    history.delivered(&pub_key, seq);
+                }
+            }
+            WalRecord::Queued { to, queued } => {
+                let mailbox = self.mailboxes.entry(to).or_default();
+                mailbox.push_queued(*queued, &self.settigns.mailbox);
+            }
+            WalRecord::Dequeued(pub_key) => {
+                self.mailboxes.remove(&pub_key);
+            }
+            WalRecord::Sent { to, message } => {
+                let key = (message.from, message.id.clone());
+                self.unacked.entry(to).or_default().insert(key, *message);
+            }
+            WalRecord::Acked { to, from, id } => self.remove_unacked(&to, from, id),
+            WalRecord::Scheduled(message) => self.schedule(*message),
+            WalRecord::Unscheduled { from, id } => {
+                self.scheduled.remove(&(from, id));
+            }
+            // Checkpoint markers are handled by log replay
+            WalRecord::CheckpointBegin | WalRecord::CheckpointEnd => {}
+        }
+    }
+
+    /// Append state change record to write-ahead log
+    fn log(&mut self, record: WalRecord) {
+        if let Some(wal) = self.wal.as_mut() {
+            if let Err(err) = wal.append(&record) {
+                eprintln!("WAL write error: {}", err);
+            }
+        }
+    }
+
+    /// Add subscriber to specific Event. Subscriber has one subscription
+    /// per event, so subscription with other group replaces previous one.
+    fn subscribe(
+        &mut self,
+        event_name: String,
+        pub_key: PublicKey,
+        durable: bool,
+        group: Option<String>,
+    ) {
+        if let Some(old_group) = self
+            .events
+            .subscriptions
+            .get(&pub_key)
+            .and_then(|events| events.get(&event_name))
+        {
+            let old_group = old_group.clone();
+            self.events
+                .subscribers
+                .remove(&event_name, &pub_key, old_group.as_deref());
+        }
+        if durable {
+            let history = self.events.durable.entry(event_name.clone()).or_default();
+            history.subscribe(pub_key);
+        }
+        self.events
+            .subscribers
+            .insert(&event_name, pub_key, group.as_deref());
+        self.events
+            .subscriptions
+            .entry(pub_key)
+            .or_default()
+            .insert(event_name, group);
+    }
+
+    /// Remove subscriber from specific Event
+    fn unsubscribe(&mut self, event_name: &str, pub_key: &PublicKey) {
+        if let Some(events) = self.events.subscriptions.get_mut(pub_key) {
+            if let Some(group) = events.remove(event_name) {
+                // Trie removes events without subscribers
+                self.events
+                    .subscribers
+                    .remove(event_name, pub_key, group.as_deref());
+            }
+            if events.is_empty() {
+                self.events.subscriptions.remove(pub_key);
+            }
+        }
+        if let Some(history) = self.events.durable.get_mut(event_name) {
+            history.unsubscribe(pub_key);
+            if history.is_empty() {
+                self.events.durable.remove(event_name);
+            }
+        }
+    }
+
+    /// Remove not durable subscriptions of disconnected subscriber.
+    /// Durable subscriptions are kept until UNSUB.
+    fn remove_subscriptions(&mut self, pub_key: &PublicKey) {
+        let events: Vec<String> = match self.events.subscriptions.get(pub_key) {
+            Some(events) => events.keys().cloned().collect(),
+            None => return,
+        };
+        for event_name in events {
+            let durable = self
+                .events
+                .durable
+                .get(&event_name)
+                .is_some_and(|history| history.is_subscribed(pub_key));
+            if !durable {
+                self.log(WalRecord::Unsubscribed {
+                    event: event_name.clone(),
+                    pub_key: *pub_key,
+                });
+                self.unsubscribe(&event_name, pub_key);
+            }
+        }
+    }
+
+    /// Set durable subscriber position to delivered message
+    fn set_delivered(&mut self, event_name: &str, pub_key: PublicKey, seq: u64) {
+        let delivered = self
+            .events
+            .durable
+            .get_mut(event_name)
+            .is_some_and(|history| history.delivered(&pub_key, seq));
+        if delivered {
+            self.log(WalRecord::Delivered {
+                event: event_name.to_owned(),
+                pub_key,
+                seq,
+            });
+        }
+    }
+
+    /// Send PUB message to subscribers of all patterns matching
+    /// event name, and retain it for durable subscribers.
+    fn publish(&mut self, event_name: &str, msg: &MqMessage) {
+        let capacity = self.settigns.history.capacity;
+        let mut sent = HashSet::new();
+        let mut published = vec![];
+        let mut delivered = vec![];
+        let mut dropped = vec![];
+        for matched in self.events.subscribers.matches(event_name) {
+            let pattern = matched.pattern;
+            // Retain message for durable subscribers, oldest
+            // messages are dropped when history is full
+            let seq = match self.events.durable.get_mut(pattern) {
+                Some(history) => {
+                    let (seq, removed) = history.push(msg.clone(), capacity);
+                    for (removed_seq, removed) in removed {
+                        for subscriber in history.lagging(removed_seq) {
+                            dropped.push((removed.clone(), *subscriber));
+                        }
+                    }
+                    published.push((pattern.to_owned(), seq));
+                    Some(seq)
+                }
+                None => None,
+            };
+            // Subscriber of several matching patterns
+            // receives message only once
+            for subscriber in matched.subscribers {
+                if self.sessions.contains_key(subscriber) {
+                    sent.insert(*subscriber);
+                    if let Some(seq) = seq {
+                        delivered.push((pattern.to_owned(), *subscriber, seq));
+                    }
+                }
+            }
+            // Only one online member of every queue group receives message
+            for group in matched.groups {
+                let member = group.next_member(|member| {
+                    self.sessions.contains_key(member) && !sent.contains(member)
+                });
+                if let Some(member) = member {
+                    sent.insert(member);
+                }
+            }
+        }
+        for subscriber in sent {
+            self.send(subscriber, msg.clone());
+        }
+        for (pattern, seq) in published {
+            self.log(WalRecord::Published {
+                event: pattern,
+                seq,
+                message: Box::new(msg.clone()),
+            });
+        }
+        for (pattern, subscriber, seq) in delivered {
+            self.set_delivered(&pattern, subscriber, seq);
+        }
+        // Notify publishers about messages lost by durable subscribers
+        for (message, subscriber) in dropped {
+            println!("History full, message dropped: {}", message.id);
+            self.respond(
+                message.from,
+                Some(subscriber),
+                message.id,
+                MessageSendStatus::Dropped,
+            );
+        }
+    }
+
+    /// Keep last PUB message of event for new subscribers,
+    /// empty message clears kept message. Returns false
+    /// if message only clears kept message.
+    fn retain(&mut self, event_name: &str, msg: &MqMessage) -> bool {
+        if msg.body.0.is_empty() {
+            self.log(WalRecord::Unretained {
+                event: event_name.to_owned(),
+            });
+            self.events.retained.remove(event_name);
+            return false;
+        }
+        self.log(WalRecord::Retained {
+            event: event_name.to_owned(),
+            message: Box::new(msg.clone()),
+        });
+        self.events
+            .retained
+            .insert(event_name.to_owned(), msg.clone());
+        true
+    }
+
+    /// Send retained messages of events matching
+    /// subscription pattern to new subscriber
+    fn deliver_retained(&self, pattern: &str, pub_key: &PublicKey) {
+        let addr = match self.sessions.get(pub_key) {
+            Some(addr) => addr,
+            None => return,
+        };
+        for (event_name, message) in self.events.retained.iter() {
+            if topic::is_match(pattern, event_name) {
+                addr.do_send(session::MqSessionMessage(message.clone()));
+            }
+        }
+    }
+
+    /// Deliver messages published while durable subscriber was offline
+    fn deliver_history(&mut self, pub_key: PublicKey) {
+        if !self.sessions.contains_key(&pub_key) {
+            return;
+        }
+        let (messages, delivered) = self.pending_history(&pub_key);
+        for message in messages {
+            self.send(pub_key, message);
+        }
+        for (event_name, seq) in delivered {
+            self.set_delivered(&event_name, pub_key, seq);
+        }
+    }
+
+    /// Messages not delivered to durable subscriber yet, and last
+    /// pending sequence number of every event history. Message
+    /// retained by several histories of matching patterns is
+    /// returned only once.
+    fn pending_history(&self, pub_key: &PublicKey) -> (Vec<MqMessage>, Vec<(String, u64)>) {
+        let mut seen = HashSet::new();
+        let mut messages = vec![];
+        let mut delivered = vec![];
+        for (event_name, history) in self.events.durable.iter() {
+            let pending = history.pending(pub_key);
+            if let Some((seq, _)) = pending.last() {
+                delivered.push((event_name.clone(), *seq));
+            }
+            for (_, message) in pending {
+                if seen.insert((message.from, message.id.clone())) {
+                    messages.push(message);
+                }
+            }
+        }
+        (messages, delivered)
+    }
+
+    /// Hand message to online recipient session. Message is
+    /// logged until session reports it acknowledged or dropped,
+    /// and is redelivered after Node restart otherwise.
+    fn send(&mut self, to: PublicKey, msg: MqMessage) {
+        let addr = match self.sessions.get(&to) {
+            Some(addr) => addr.clone(),
+            None => return,
+        };
+        self.log(WalRecord::Sent {
+            to,
+            message: Box::new(msg.clone()),
+        });
+        let key = (msg.from, msg.id.clone());
+        self.unacked.entry(to).or_default().insert(key, msg.clone());
+        addr.do_send(session::MqSessionMessage(msg));
+    }
+
+    /// Message handed to recipient session is done
+    fn acked(&mut self, to: PublicKey, from: PublicKey, id: String) {
+        let removed = self
+            .unacked
+            .get(&to)
+            .is_some_and(|messages| messages.contains_key(&(from, id.clone())));
+        if removed {
+            self.log(WalRecord::Acked {
+                to,
+                from,
+                id: id.clone(),
+            });
+            self.remove_unacked(&to, from, id);
+        }
+    }
+
+    /// Remove message handed to recipient session
+    fn remove_unacked(&mut self, to: &PublicKey, from: PublicKey, id: String) {
+        if let Some(messages) = self.unacked.get_mut(to) {
+            messages.remove(&(from, id));
+            if messages.is_empty() {
+                self.unacked.remove(to);
+            }
+        }
+    }
+
+    /// Wait for reply for REQ/REP request delivered to recipient.
+    /// Requester receives `Timeout` status if reply is not
+    /// received during request timeout.
+    fn track_request(&mut self, msg: &MqMessage, ctx: &mut Context<Self>) {
+        let responder = match (&msg.protocol, msg.to) {
+            (ReqRep, Some(to)) => to,
+            _ => return,
+        };
+        let key = (msg.from, msg.id.clone());
+        let timeout_key = key.clone();
+        let timeout = ctx.run_later(
+            Duration::new(self.settigns.request_timeout_sec, 0),
+            move |act, _| {
+                let (requester, id) = timeout_key;
+                if act.requests.remove(&(requester, id.clone())).is_none() {
+                    return;
+                }
+                println!("Request timeout: {}", id);
+                act.respond(requester, Some(responder), id, MessageSendStatus::Timeout);
+            },
+        );
+        let request = PendingRequest { responder, timeout };
+        if let Some(old) = self.requests.insert(key, request) {
+            ctx.cancel_future(old.timeout);
+        }
+    }
+
+    /// Complete pending REQ/REP request when reply received.
+    /// Returns false for reply without pending request,
+    /// for example when request timed out.
+    fn complete_request(&mut self, msg: &MqMessage, ctx: &mut Context<Self>) -> bool {
+        let (correlation_id, requester) = match (&msg.protocol, msg.to) {
+            (Reply { correlation_id }, Some(to)) => (correlation_id, to),
+            _ => return true,
+        };
+        let key = (requester, correlation_id.clone());
+        match self.requests.get(&key) {
+            // Only request recipient can reply
+            Some(request) if request.responder == msg.from => {
+                let request = self.requests.remove(&key).unwrap();
+                ctx.cancel_future(request.timeout);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Check message before routing, message
+    /// is rejected if check failed.
+    fn validate(&mut self, msg: &MqMessage, ctx: &mut Context<Self>) -> Result<(), RejectReason> {
+        if !self.is_signature_valid(msg) {
+            return Err(RejectReason::InvalidSignature);
+        }
+        match (&msg.protocol, &msg.event) {
+            // PUB event name can't contain wildcards
+            (Pub { .. }, Some(event_name)) if topic::is_valid_name(event_name) => Ok(()),
+            // Queue group members compete for messages,
+            // so group subscription can't be durable
+            (
+                Sub {
+                    durable: true,
+                    group: Some(_),
+                },
+                _,
+            ) => Err(RejectReason::InvalidSubscription),
+            (Sub { .. }, Some(event_name)) | (UnSub, Some(event_name))
+                if topic::is_valid_pattern(event_name) =>
+            {
+                Ok(())
+            }
+            (Pub { .. }, _) | (Sub { .. }, _) | (UnSub, _) => Err(RejectReason::InvalidEvent),
+            // Peer `to` is not set
+            _ if msg.to.is_none() => Err(RejectReason::PeerNotFound),
+            // Reply for timed out or unknown request
+            (Reply { .. }, _) if !self.complete_request(msg, ctx) => {
+                Err(RejectReason::RequestNotFound)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Check and route message received from client,
+    /// returns message status after routing
+    fn accept(&mut self, msg: MqMessage, ctx: &mut Context<Self>) -> MessageSendStatus {
+        let (from, to, id) = (msg.from, msg.to, msg.id.clone());
+        // Drop retried message already accepted
+        if self.accepted.contains(&from, &id, &self.settigns.dedup) {
+            return MessageSendStatus::Duplicate;
+        }
+        let now = SystemTime::now();
+        if msg.is_expired(now) {
+            return MessageSendStatus::Expired;
+        }
+        if let Err(reason) = self.validate(&msg, ctx) {
+            return MessageSendStatus::Rejected(reason);
+        }
+        self.respond(from, to, id.clone(), MessageSendStatus::Accepted);
+        let status = if msg.is_delayed(now) {
+            self.log(WalRecord::Scheduled(Box::new(msg.clone())));
+            self.schedule(msg);
+            MessageSendStatus::Scheduled
+        } else {
+            self.route(msg, ctx)
+        };
+        // Message rejected by routing can be retried
+        if !matches!(status, MessageSendStatus::Rejected(_)) {
+            self.accepted.insert(from, id, &self.settigns.dedup);
+        }
+        status
+    }
+
+    /// Route accepted message to recipients
+    fn route(&mut self, msg: MqMessage, ctx: &mut Context<Self>) -> MessageSendStatus {
+        match msg.protocol.clone() {
+            Pub { retain } => {
+                let event_name = msg.event.clone().unwrap();
+                if !retain || self.retain(&event_name, &msg) {
+                    self.publish(&event_name, &msg);
+                }
+                MessageSendStatus::Routed
+            }
+            Sub { durable, group } => {
+                let event_name = msg.event.unwrap();
+                self.log(WalRecord::Subscribed {
+                    event: event_name.clone(),
+                    pub_key: msg.from,
+                    durable,
+                    group: group.clone(),
+                });
+                self.subscribe(event_name.clone(), msg.from, durable, group);
+                self.deliver_retained(&event_name, &msg.from);
+                MessageSendStatus::Routed
+            }
+            UnSub => {
+                let event_name = msg.event.unwrap();
+                self.log(WalRecord::Unsubscribed {
+                    event: event_name.clone(),
+                    pub_key: msg.from,
+                });
+                self.unsubscribe(&event_name, &msg.from);
+                MessageSendStatus::Routed
+            }
+            ReqRep | Reply { .. } => match msg.to {
+                Some(to) if self.sessions.contains_key(&to) => {
+                    // Send message to peer `to`
+                    // and wait for reply of request
+                    self.track_request(&msg, ctx);
+                    self.send(to, msg);
+                    MessageSendStatus::Routed
+                }
+                // Peer `to` is offline, message will be
+                // delivered when peer registered
+                Some(to) if self.known.contains(&to) => self.enqueue(to, msg),
+                _ => MessageSendStatus::Rejected(RejectReason::PeerNotFound),
+            },
+        }
+    }
+
+    /// Hold message until delivery time
+    fn schedule(&mut self, msg: MqMessage) {
+        let key = (msg.from, msg.id.clone());
+        if let Some(deliver_at) = msg.deliver_at {
+            self.timers.insert(deliver_at, key.clone());
+        }
+        self.scheduled.insert(key, msg);
+    }
+
+    /// Route scheduled messages which delivery time come.
+    /// Message expired while waiting is discarded.
+    fn route_scheduled(&mut self, ctx: &mut Context<Self>) {
+        let now = SystemTime::now();
+        for key in self.timers.advance(now) {
+            let msg = match self.scheduled.remove(&key) {
+                Some(msg) => msg,
+                None => continue,
+            };
+            let (from, id) = key;
+            self.log(WalRecord::Unscheduled {
+                from,
+                id: id.clone(),
+            });
+            let to = msg.to;
+            let status = if msg.is_expired(now) {
+                MessageSendStatus::Expired
+            } else {
+                self.route(msg, ctx)
+            };
+            self.respond(from, to, id, status);
+        }
+    }
+
+    /// Send message status to message sender
+    fn respond(
+        &self,
+        from: PublicKey,
+        to: Option<PublicKey>,
+        id: String,
+        status: MessageSendStatus,
+    ) {
+        if let Some(addr) = self.sessions.get(&from) {
+            addr.do_send(MqMessageResponse {
+                from,
+                to,
+                id,
+                status,
+            });
+        }
+    }
+
+    /// Current presence of clients
+    fn presence_status(&self, keys: Vec<PublicKey>) -> Vec<codec::PresenceStatus> {
+        keys.into_iter()
+            .map(|pub_key| codec::PresenceStatus {
+                pub_key,
+                online: self.sessions.contains_key(&pub_key),
+            })
+            .collect()
+    }
+
+    /// Notify watchers about client presence change
+    fn notify_presence(&self, pub_key: PublicKey, online: bool) {
+        for watcher in self.presence.watchers(&pub_key) {
+            if let Some(addr) = self.sessions.get(watcher) {
+                addr.do_send(session::MqSessionPresence(codec::PresenceStatus {
+                    pub_key,
+                    online,
+                }));
+            }
+        }
+    }
+
+    /// Queue message for known offline recipient
+    fn enqueue(&mut self, to: PublicKey, msg: MqMessage) -> MessageSendStatus {
+        let queued = QueuedMessage {
+            queued_at: SystemTime::now(),
+            message: msg,
+        };
+        let mailbox = self.mailboxes.entry(to).or_default();
+        if mailbox.push_queued(queued.clone(), &self.settigns.mailbox) {
+            self.log(WalRecord::Queued {
+                to,
+                queued: Box::new(queued),
+            });
+            MessageSendStatus::Queued
+        } else {
+            MessageSendStatus::Rejected(RejectReason::MailboxFull)
+        }
+    }
+
+    /// Check message signature according to Node signature policy
+    fn is_signature_valid(&self, msg: &MqMessage) -> bool {
+        match self.settigns.signature_policy {
+            SignaturePolicy::Off => true,
+            SignaturePolicy::VerifyIfPresent => msg.signature.is_none() || msg.verify(),
+            SignaturePolicy::Require => msg.verify(),
+        }
+    }
 }
 
 /// Make actor from `MqServer`
@@ -41,6 +835,47 @@ impl Actor for MqServer {
     /// We are going to use simple Context, we just need ability to communicate
     /// with other actors.
     type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        // Route scheduled messages when delivery time come
+        ctx.run_interval(Duration::from_millis(SCHEDULE_TICK_MS), |act, ctx| {
+            act.route_scheduled(ctx);
+        });
+
+        // Remove expired messages from offline clients mailboxes
+        // and expired retained messages
+        ctx.run_interval(Duration::new(MAILBOX_PURGE_SEC, 0), |act, _| {
+            let cfg = &act.settigns.mailbox;
+            for mailbox in act.mailboxes.values_mut() {
+                mailbox.purge_expired(cfg);
+            }
+            act.mailboxes.retain(|_, mailbox| !mailbox.is_empty());
+            let now = SystemTime::now();
+            act.events
+                .retained
+                .retain(|_, message| !message.is_expired(now));
+            // Refilled rate limiter is same as new one
+            let now = Instant::now();
+            act.limiters.retain(|_, limiter| !limiter.is_full(now));
+        });
+
+        // Compact write-ahead log when it grows
+        ctx.run_interval(Duration::new(WAL_CHECKPOINT_SEC, 0), |act, _| {
+            if act.wal.as_ref().is_some_and(|wal| wal.needs_checkpoint()) {
+                act.checkpoint();
+            }
+        });
+
+        if self.settigns.wal.as_ref().map(|wal| wal.fsync) == Some(FsyncPolicy::EverySecond) {
+            ctx.run_interval(Duration::new(WAL_SYNC_SEC, 0), |act, _| {
+                if let Some(wal) = act.wal.as_mut() {
+                    if let Err(err) = wal.sync() {
+                        eprintln!("WAL sync error: {}", err);
+                    }
+                }
+            });
+        }
+    }
 }
 
 /// Message for MQ server communications
@@ -61,6 +896,13 @@ impl actix::Message for Connect {
 #[derive(Message)]
 pub struct Disconnect(pub PublicKey);
 
+/// Session returns registered client rate limiter
+#[derive(Message)]
+pub struct MqRateLimiter {
+    pub pub_key: PublicKey,
+    pub limiter: RateLimiter,
+}
+
 /// Basic MQ Message Data
 #[derive(Message, Debug, Deserialize, Serialize, Clone)]
 pub struct MqMessage {
@@ -72,7 +914,16 @@ pub struct MqMessage {
     pub protocol: codec::MessageProtocol,
     pub time: SystemTime,
     pub nonce: Option<cipher::Nonce>,
-    pub body: String,
+    pub body: codec::MessageBody,
+    #[serde(default)]
+    pub content_type: Option<String>,
+    #[serde(default)]
+    pub expires_at: Option<SystemTime>,
+    #[serde(default)]
+    pub deliver_at: Option<SystemTime>,
+    /// Message redelivery count, set by Node
+    #[serde(default)]
+    pub redelivered: u32,
 }
 
 #[allow(dead_code)]
@@ -88,29 +939,40 @@ impl MqMessage {
             time: self.time,
             nonce: self.nonce,
             body: self.body.clone(),
+            content_type: self.content_type.clone(),
+            expires_at: self.expires_at,
+            deliver_at: self.deliver_at,
         }
     }
 
     /// Verify message signature
-    pub fn verify(&mut self) -> bool {
-        if self.signature.is_none() {
-            return false;
-        }
-        let mut msg = self.to_message();
-        msg.signature = None;
-        let data = json::to_string(&msg).expect("Message should be serialize to JSON");
-        sign::verify(&self.signature.unwrap(), data.as_bytes(), &self.from)
+    pub fn verify(&self) -> bool {
+        sign::verify_message(&self.to_message(), &self.from)
+    }
+
+    /// Check is message expiration time passed
+    pub fn is_expired(&self, now: SystemTime) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
     }
-}
 
-/// Public Message Events subscribers
-pub type MessageEventsSubscribers = HashMap<String, Vec<PublicKey>>;
+    /// Check is message delivery time not come yet
+    pub fn is_delayed(&self, now: SystemTime) -> bool {
+        self.deliver_at.is_some_and(|deliver_at| deliver_at > now)
+    }
+}
 
 /// Message Events data
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub struct MessageEvents {
-    /// PUBSUB subscribers
-    pub subscribers: MessageEventsSubscribers,
+    /// PUBSUB subscribers by event patterns
+    pub subscribers: TopicTrie,
+    /// Retained messages for durable subscribers by event patterns
+    pub durable: HashMap<String, EventHistory>,
+    /// Subscribed event patterns of every subscriber
+    /// with subscription queue group
+    pub subscriptions: HashMap<PublicKey, HashMap<String, Option<String>>>,
+    /// Last retained PUB message by event name
+    pub retained: HashMap<String, MqMessage>,
 }
 
 /// Sent Message response data
@@ -118,6 +980,8 @@ pub struct MessageEvents {
 pub struct MqMessageResponse {
     pub from: PublicKey,
     pub to: Option<PublicKey>,
+    /// Message id
+    pub id: String,
     pub status: MessageSendStatus,
 }
 
@@ -135,6 +999,57 @@ pub struct MqPongClient {
     pub to: PublicKey,
 }
 
+/// Set or clear client last-will message
+#[derive(Message)]
+pub struct MqLastWill {
+    pub pub_key: PublicKey,
+    pub message: Option<MqMessage>,
+}
+
+/// Query clients presence
+pub struct MqPresence(pub Vec<PublicKey>);
+
+/// Response type for Presence message
+impl actix::Message for MqPresence {
+    type Result = Vec<codec::PresenceStatus>;
+}
+
+/// Subscribe to clients presence changes
+pub struct MqPresenceSub {
+    pub watcher: PublicKey,
+    pub keys: Vec<PublicKey>,
+}
+
+/// Response type for Presence Sub message,
+/// current presence of clients
+impl actix::Message for MqPresenceSub {
+    type Result = Vec<codec::PresenceStatus>;
+}
+
+/// Unsubscribe from clients presence changes
+#[derive(Message)]
+pub struct MqPresenceUnSub {
+    pub watcher: PublicKey,
+    pub keys: Vec<PublicKey>,
+}
+
+/// Return not acknowledged messages of
+/// closed session for redelivery
+#[derive(Message)]
+pub struct MqRequeue {
+    pub to: PublicKey,
+    pub messages: Vec<MqMessage>,
+}
+
+/// Message handed to session is acknowledged by
+/// client, or dropped or expired by session
+#[derive(Message)]
+pub struct MqAcked {
+    pub to: PublicKey,
+    pub from: PublicKey,
+    pub id: String,
+}
+
 /// Register client
 pub struct MqRegister {
     /// Old client identifier
@@ -145,19 +1060,91 @@ pub struct MqRegister {
     pub pub_key: PublicKey,
 }
 
-/// Message send statuses
-#[derive(Debug, Serialize, Deserialize)]
+/// Publish client box_ PublicKey
+#[derive(Message)]
+pub struct MqPublishKey {
+    pub pub_key: PublicKey,
+    pub key: codec::BoxKey,
+}
+
+/// Lookup client box_ PublicKey
+pub struct MqLookupKey(pub PublicKey);
+
+/// Response type for LookupKey message
+impl actix::Message for MqLookupKey {
+    type Result = Option<codec::BoxKey>;
+}
+
+/// Message send statuses.
+///
+/// Message sender receives status for every message lifecycle step:
+/// - REQ/REP request: `Accepted`, `Routed` or `Queued`, `Delivered`,
+///   `Received` and `Timeout` if reply not received in time
+/// - REQ/REP reply: `Accepted`, `Routed` or `Queued`, `Delivered`
+/// - PUB/SUB/UNSUB: `Accepted`, `Routed`
+///
+/// Message with delivery time in the future is `Scheduled` after
+/// `Accepted`, and continues lifecycle when delivery time come.
+/// `Rejected` and `Expired` finish lifecycle on any step. Slow recipient
+/// reports `Dropped` instead of `Delivered` when message is dropped from
+/// its full outbound queue, also for PUB messages. Offline durable
+/// subscriber reports `Dropped` when PUB message is removed from full
+/// event history before delivery. Message with same
+/// sender and id as recently accepted message is dropped with
+/// `Duplicate` status. Message exceeding sender rate limit is
+/// not accepted and receives only `RateLimited` status.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum MessageSendStatus {
-    Sent,
+    /// Message checked and accepted by Node
+    Accepted,
+    /// Message sent to recipients, or subscription is changed
+    Routed,
+    /// Message queued in offline recipient mailbox
+    Queued,
+    /// Message written to recipient connection
+    Delivered,
+    /// Recipient confirmed message receiving
     Received,
+    /// Reply for request not received during request timeout
+    Timeout,
+    /// Message already accepted, retried message is dropped
+    Duplicate,
+    /// Message held by Node until delivery time
+    Scheduled,
+    /// Message expiration time passed, message is discarded
+    Expired,
+    /// Message dropped by slow recipient full outbound queue
+    /// or by full event history of durable subscriber
+    Dropped,
+    /// Sender exceeded rate limit, message is not accepted
+    RateLimited,
+    /// Message is not routed
+    Rejected(RejectReason),
+}
+
+/// Message rejection reasons
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum RejectReason {
+    /// Signature is invalid or required by Node policy
+    InvalidSignature,
+    /// Event name is missing or invalid
+    InvalidEvent,
+    /// Subscription options are not compatible
+    InvalidSubscription,
+    /// Recipient is not set or unknown
     PeerNotFound,
-    Failed,
+    /// Offline recipient mailbox is full
+    MailboxFull,
+    /// Reply for unknown or timed out request
+    RequestNotFound,
+    /// Sender is not registered
+    NotRegistered,
 }
 
 /// Response type for Register message
-/// It can be success or fail
+/// It can be success or fail with error reason
 impl actix::Message for MqRegister {
-    type Result = Option<PublicKey>;
+    type Result = Result<(PublicKey, RateLimiter), codec::MqError>;
 }
 
 /// Handler for Connect message.
@@ -179,11 +1166,24 @@ impl Handler<Connect> for MqServer {
 impl Handler<Disconnect> for MqServer {
     type Result = ();
 
-    fn handle(&mut self, msg: Disconnect, _: &mut Context<Self>) {
+    fn handle(&mut self, msg: Disconnect, ctx: &mut Context<Self>) {
         let pub_key = msg.0;
         println!("Handler<Disconnect>");
         // Unregister session
-        self.sessions.remove(&pub_key);
+        let online = self.sessions.remove(&pub_key).is_some();
+        self.remove_subscriptions(&pub_key);
+        self.presence.remove_watcher(&pub_key);
+        if online {
+            self.notify_presence(pub_key, false);
+        }
+
+        // Session ended without clean disconnect
+        if let Some(will) = self.wills.remove(&pub_key) {
+            println!("Publish last will: {}", will.id);
+            if !will.is_expired(SystemTime::now()) {
+                self.route(will, ctx);
+            }
+        }
     }
 }
 
@@ -191,70 +1191,11 @@ impl Handler<Disconnect> for MqServer {
 impl Handler<MqMessage> for MqServer {
     type Result = ();
 
-    fn handle(&mut self, msg: MqMessage, _: &mut Context<Self>) {
+    fn handle(&mut self, msg: MqMessage, ctx: &mut Context<Self>) {
         println!("Handler<Message>");
-        let msg_data = msg.clone();
-        // Send message and set message status response
-        let status = if (msg.protocol == Pub || msg.protocol == Sub || msg.protocol == UnSub)
-            && msg.event.is_some()
-        {
-            match msg.protocol {
-                Pub => {
-                    let event_name = msg.event.clone().unwrap();
-                    // Send message to subscribers
-                    if let Some(event) = self.events.subscribers.get(&event_name) {
-                        for subscriber in event {
-                            if let Some(addr) = self.sessions.get(subscriber) {
-                                addr.do_send(session::MqSessionMessage(msg.clone()));
-                            }
-                        }
-                    }
-                }
-                Sub => {
-                    let event_name = msg.event.unwrap();
-                    // Add subscriber to specific Event
-                    if let Some(event) = self.events.subscribers.get_mut(&event_name) {
-                        event.push(msg.from);
-                    } else {
-                        self.events.subscribers.insert(event_name, vec![msg.from]);
-                    }
-                }
-                UnSub => {
-                    let event_name = msg.event.clone().unwrap();
-                    // Remove subscriber from specific Event
-                    if let Some(event) = self.events.subscribers.get_mut(&event_name) {
-                        let from = msg.from;
-                        let element = event.iter().position(|x| *x == from);
-                        if element.is_some() {
-                            let _ = event.remove(element.unwrap());
-                        }
-                    }
-                }
-                _ => {}
-            }
-            MessageSendStatus::Sent
-        } else if msg.to.is_none() {
-            // Check is set peer `to`
-            MessageSendStatus::PeerNotFound
-        } else if let Some(addr) = self.sessions.get(&msg.to.unwrap()) {
-            // Send message to peer `to`
-            addr.do_send(session::MqSessionMessage(msg));
-            MessageSendStatus::Sent
-        } else if self.sessions.get(&msg.from).is_some() {
-            // We found `from` peer but not found peer `to`
-            MessageSendStatus::PeerNotFound
-        } else {
-            MessageSendStatus::Failed
-        };
-
-        // Send message response to peer
-        if let Some(addr) = self.sessions.get(&msg_data.from) {
-            addr.do_send(MqMessageResponse {
-                from: msg_data.from,
-                to: msg_data.to,
-                status,
-            });
-        }
+        let (from, to, id) = (msg.from, msg.to, msg.id.clone());
+        let status = self.accept(msg, ctx);
+        self.respond(from, to, id, status);
     }
 }
 
@@ -262,23 +1203,158 @@ impl Handler<MqMessage> for MqServer {
 impl Handler<MqRegister> for MqServer {
     type Result = MessageResult<MqRegister>;
 
-    fn handle(&mut self, msg: MqRegister, _: &mut Context<Self>) -> Self::Result {
+    fn handle(&mut self, msg: MqRegister, ctx: &mut Context<Self>) -> Self::Result {
         println!("Handler<Register>");
 
         // Check is Client already registered
-        if self.sessions.get(&msg.pub_key).is_some() {
+        if self.sessions.contains_key(&msg.pub_key) {
             eprintln!("Client already registered - close session");
-            return MessageResult(None);
+            return MessageResult(Err(codec::MqError::AlreadyRegistered));
         }
 
-        if let Some(addr) = self.sessions.get(&msg.old_pub_key) {
-            self.sessions.insert(msg.pub_key, addr.to_owned());
-            self.sessions.remove(&msg.old_pub_key);
+        if let Some(addr) = self.sessions.remove(&msg.old_pub_key) {
+            self.sessions.insert(msg.pub_key, addr);
+            // Messages lost by previous session before
+            // they were requeued are sent again
+            if let Some(messages) = self.unacked.remove(&msg.pub_key) {
+                for (_, mut message) in messages {
+                    message.redelivered += 1;
+                    self.send(msg.pub_key, message);
+                }
+            }
+            // Deliver messages queued while client was offline
+            if let Some(mut mailbox) = self.mailboxes.remove(&msg.pub_key) {
+                for message in mailbox.drain(&self.settigns.mailbox) {
+                    self.track_request(&message, ctx);
+                    self.send(msg.pub_key, message);
+                }
+                self.log(WalRecord::Dequeued(msg.pub_key));
+            }
+            if self.known.insert(msg.pub_key) {
+                self.log(WalRecord::Registered(msg.pub_key));
+            }
+            self.notify_presence(msg.pub_key, true);
+            self.deliver_history(msg.pub_key);
         } else {
             eprintln!("Session address not found");
-            return MessageResult(None);
+            return MessageResult(Err(codec::MqError::SessionNotFound));
+        }
+        // Rate limit state is kept between client sessions
+        let limiter = match self.limiters.remove(&msg.pub_key) {
+            Some(limiter) => limiter,
+            None => RateLimiter::new(&self.settigns.rate_limit.limit(&msg.pub_key)),
+        };
+        MessageResult(Ok((msg.pub_key, limiter)))
+    }
+}
+
+/// Handler for rate limiter of disconnected client
+impl Handler<MqRateLimiter> for MqServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: MqRateLimiter, _: &mut Context<Self>) {
+        self.limiters.insert(msg.pub_key, msg.limiter);
+    }
+}
+
+/// Handler for Last Will message.
+impl Handler<MqLastWill> for MqServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: MqLastWill, ctx: &mut Context<Self>) {
+        println!("Handler<MqLastWill>");
+        let will = match msg.message {
+            Some(will) => will,
+            None => {
+                self.wills.remove(&msg.pub_key);
+                return;
+            }
+        };
+        let (from, id) = (will.from, will.id.clone());
+        let status = match will.protocol {
+            _ if !self.known.contains(&from) => Err(RejectReason::NotRegistered),
+            // Last will can be only PUB message
+            Pub { .. } => self.validate(&will, ctx),
+            _ => Err(RejectReason::InvalidEvent),
+        };
+        let status = match status {
+            Ok(()) => {
+                self.wills.insert(from, will);
+                MessageSendStatus::Accepted
+            }
+            Err(reason) => MessageSendStatus::Rejected(reason),
+        };
+        self.respond(from, None, id, status);
+    }
+}
+
+/// Handler for Presence message.
+impl Handler<MqPresence> for MqServer {
+    type Result = MessageResult<MqPresence>;
+
+    fn handle(&mut self, msg: MqPresence, _: &mut Context<Self>) -> Self::Result {
+        println!("Handler<MqPresence>");
+        MessageResult(self.presence_status(msg.0))
+    }
+}
+
+/// Handler for Presence Sub message.
+impl Handler<MqPresenceSub> for MqServer {
+    type Result = MessageResult<MqPresenceSub>;
+
+    fn handle(&mut self, msg: MqPresenceSub, _: &mut Context<Self>) -> Self::Result {
+        println!("Handler<MqPresenceSub>");
+        // Only registered client can watch, session
+        // temporary pub_key is changed on registration
+        if self.known.contains(&msg.watcher) {
+            self.presence.watch(msg.watcher, &msg.keys);
+        }
+        MessageResult(self.presence_status(msg.keys))
+    }
+}
+
+/// Handler for Presence UnSub message.
+impl Handler<MqPresenceUnSub> for MqServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: MqPresenceUnSub, _: &mut Context<Self>) {
+        println!("Handler<MqPresenceUnSub>");
+        self.presence.unwatch(&msg.watcher, &msg.keys);
+    }
+}
+
+/// Handler for Requeue message.
+impl Handler<MqRequeue> for MqServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: MqRequeue, _: &mut Context<Self>) {
+        println!("Handler<MqRequeue>: {}", msg.messages.len());
+        for message in msg.messages {
+            let (from, id) = (message.from, message.id.clone());
+            if self.sessions.contains_key(&msg.to) {
+                // Client already reconnected
+                self.send(msg.to, message);
+                continue;
+            }
+            if self.known.contains(&msg.to) {
+                // Redeliver when client registered
+                if let MessageSendStatus::Rejected(reason) = self.enqueue(msg.to, message) {
+                    eprintln!("Requeue message failed: {:?}", reason);
+                }
+            } else {
+                eprintln!("Requeue message failed: client not registered");
+            }
+            self.acked(msg.to, from, id);
         }
-        MessageResult(Some(msg.pub_key))
+    }
+}
+
+/// Handler for Acked message.
+impl Handler<MqAcked> for MqServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: MqAcked, _: &mut Context<Self>) {
+        self.acked(msg.to, msg.from, msg.id);
     }
 }
 
@@ -321,3 +1397,167 @@ impl Handler<MqMessageResponse> for MqServer {
         }
     }
 }
+
+/// Handler for Publish Key message.
+impl Handler<MqPublishKey> for MqServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: MqPublishKey, _: &mut Context<Self>) {
+        println!("Handler<MqPublishKey>");
+        self.log(WalRecord::KeyPublished {
+            pub_key: msg.pub_key,
+            key: msg.key.clone(),
+        });
+        self.keys.insert(msg.pub_key, msg.key);
+    }
+}
+
+/// Handler for Lookup Key message.
+impl Handler<MqLookupKey> for MqServer {
+    type Result = MessageResult<MqLookupKey>;
+
+    fn handle(&mut self, msg: MqLookupKey, _: &mut Context<Self>) -> Self::Result {
+        println!("Handler<MqLookupKey>");
+        MessageResult(self.keys.get(&msg.0).cloned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::DEFAULT_MAX_FRAME_SIZE;
+    use crate::types::{
+        DedupConfig, HistoryConfig, MailboxConfig, OutboundConfig, RateLimitConfig,
+    };
+    use sodiumoxide::crypto::sign::ed25519::SecretKey;
+
+    fn server() -> MqServer {
+        MqServer::new(NodeAppConfig {
+            public_key: PublicKey([0; 32]),
+            secret_key: SecretKey([0; 64]),
+            port: 0,
+            signature_policy: SignaturePolicy::Off,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            request_timeout_sec: 30,
+            ack_timeout_sec: 10,
+            mailbox: MailboxConfig::default(),
+            history: HistoryConfig::default(),
+            dedup: DedupConfig::default(),
+            outbound: OutboundConfig::default(),
+            rate_limit: RateLimitConfig::default(),
+            wal: None,
+        })
+    }
+
+    /// Context of not started server
+    fn context() -> Context<MqServer> {
+        let (_, rx) = actix::dev::channel::channel(16);
+        Context::with_receiver(rx)
+    }
+
+    fn request(from: PublicKey, to: PublicKey, id: &str) -> MqMessage {
+        MqMessage {
+            id: id.to_owned(),
+            from,
+            to: Some(to),
+            signature: None,
+            event: None,
+            protocol: ReqRep,
+            time: SystemTime::now(),
+            nonce: None,
+            body: "{}".into(),
+            content_type: None,
+            expires_at: None,
+            deliver_at: None,
+            redelivered: 0,
+        }
+    }
+
+    #[test]
+    fn rejected_message_can_be_retried() {
+        let (from, to) = (PublicKey([1; 32]), PublicKey([2; 32]));
+        let mut server = server();
+        let mut ctx = context();
+
+        let status = server.accept(request(from, to, "1"), &mut ctx);
+        assert_eq!(
+            status,
+            MessageSendStatus::Rejected(RejectReason::PeerNotFound)
+        );
+
+        // Recipient registered, retried message is queued
+        server.known.insert(to);
+        let status = server.accept(request(from, to, "1"), &mut ctx);
+        assert_eq!(status, MessageSendStatus::Queued);
+
+        let status = server.accept(request(from, to, "1"), &mut ctx);
+        assert_eq!(status, MessageSendStatus::Duplicate);
+    }
+
+    #[test]
+    fn history_of_overlapping_patterns_is_pending_once() {
+        let (from, subscriber) = (PublicKey([1; 32]), PublicKey([2; 32]));
+        let mut server = server();
+        server.subscribe("a.*".to_owned(), subscriber, true, None);
+        server.subscribe("a.b".to_owned(), subscriber, true, None);
+
+        let mut msg = request(from, subscriber, "1");
+        msg.to = None;
+        msg.event = Some("a.b".to_owned());
+        msg.protocol = Pub { retain: false };
+        server.publish("a.b", &msg);
+
+        let (messages, mut delivered) = server.pending_history(&subscriber);
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].id, "1");
+        delivered.sort();
+        assert_eq!(
+            delivered,
+            vec![("a.*".to_owned(), 1), ("a.b".to_owned(), 1)]
+        );
+    }
+
+    #[test]
+    fn unacked_messages_are_queued_on_restore() {
+        let (from, to) = (PublicKey([1; 32]), PublicKey([2; 32]));
+        let dir = std::env::temp_dir().join(format!("amq-server-unacked-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let cfg = crate::types::WalConfig {
+            path: dir.to_string_lossy().into_owned(),
+            segment_size: 1024 * 1024,
+            fsync: FsyncPolicy::Never,
+            checkpoint_segments: 4,
+        };
+        let (wal, _) = Wal::open(&cfg).unwrap();
+        let records = vec![
+            WalRecord::Registered(to),
+            WalRecord::Sent {
+                to,
+                message: Box::new(request(from, to, "1")),
+            },
+            WalRecord::Sent {
+                to,
+                message: Box::new(request(from, to, "2")),
+            },
+            WalRecord::Acked {
+                to,
+                from,
+                id: "1".to_owned(),
+            },
+        ];
+
+        let mut server = server();
+        server.restore(wal, records).unwrap();
+        assert!(server.unacked.is_empty());
+        let queued = server
+            .mailboxes
+            .get_mut(&to)
+            .unwrap()
+            .drain(&MailboxConfig::default());
+        assert_eq!(queued.len(), 1);
+        assert_eq!(queued[0].id, "2");
+        assert_eq!(queued[0].redelivered, 1);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -2,17 +2,36 @@ use actix::io::{FramedWrite, WriteHandler};
 use actix::prelude::*;
 use actix::Message;
 use sodiumoxide::crypto::sign::ed25519::PublicKey;
+use std::collections::{HashMap, VecDeque};
 use std::io;
-use std::time::{Duration, Instant};
+use std::mem;
+use std::time::{Duration, Instant, SystemTime};
 use tokio_io::io::WriteHalf;
 use tokio_tcp::TcpStream;
 
-use crate::codec::{MqCodec, MqRequest, MqResponse};
+use crate::codec::{
+    ByteCounter, Capability, CountingWrite, HelloAck, MqCodec, MqError, MqRequest, MqResponse,
+    PresenceStatus, RegisterChallenge, WireFormatHandle, PROTOCOL_VERSION,
+};
+use crate::ratelimit::RateLimiter;
 use crate::server::{self, MqServer};
 use crate::sign;
+use crate::types::{NodeAppConfig, SlowConsumerPolicy};
 
 const PING_TIME_SEC: u64 = 5;
 const PING_WAIT_SEC: u64 = 15;
+/// Not acknowledged messages check interval
+const ACK_CHECK_SEC: u64 = 1;
+/// Outbound queue sending interval
+const OUTBOUND_FLUSH_MS: u64 = 50;
+
+/// Capabilities supported by Node
+const NODE_CAPABILITIES: &[Capability] = &[
+    Capability::KeyDirectory,
+    Capability::Acks,
+    Capability::LastWill,
+    Capability::Presence,
+];
 
 /// MQ server sends this messages to session
 #[derive(Message)]
@@ -31,6 +50,10 @@ pub struct MqSessionPingClient(pub PublicKey);
 #[derive(Message)]
 pub struct MqSessionPongClient(pub PublicKey);
 
+/// Watched client presence changed
+#[derive(Message)]
+pub struct MqSessionPresence(pub PresenceStatus);
+
 /// `MqSession` actor is responsible for tcp peer communications.
 pub struct MqSession {
     /// MQ session NodePublicKey
@@ -41,7 +64,40 @@ pub struct MqSession {
     /// connection.
     hb: Instant,
     /// Framed wrapper
-    framed: FramedWrite<WriteHalf<TcpStream>, MqCodec>,
+    framed: FramedWrite<CountingWrite<WriteHalf<TcpStream>>, MqCodec>,
+    /// Node settings
+    settings: NodeAppConfig,
+    /// Claimed client pub_key and issued register challenge
+    challenge: Option<(PublicKey, RegisterChallenge)>,
+    /// Wire format for sending to peer
+    format: WireFormatHandle,
+    /// Capabilities enabled for session, `None` until Hello received
+    capabilities: Option<Vec<Capability>>,
+    /// Messages sent to peer and waiting for
+    /// acknowledgement, by sender and message id
+    in_flight: HashMap<(PublicKey, String), InFlight>,
+    /// Messages waiting for sending to slow peer
+    outbound: VecDeque<server::MqMessage>,
+    /// Bytes written to write buffer
+    encoded: ByteCounter,
+    /// Bytes sent from write buffer to connection
+    written: ByteCounter,
+    /// Client requests rate limiter
+    limiter: RateLimiter,
+    /// Is client pub_key registered
+    registered: bool,
+}
+
+/// Message waiting for acknowledgement
+struct InFlight {
+    /// Encoded bytes count after message was written
+    /// to write buffer, message is sent when
+    /// written bytes count reach it
+    offset: u64,
+    /// Time message was sent to connection,
+    /// `None` while message is in write buffer
+    sent: Option<Instant>,
+    message: server::MqMessage,
 }
 
 impl Actor for MqSession {
@@ -50,6 +106,10 @@ impl Actor for MqSession {
     fn started(&mut self, ctx: &mut Self::Context) {
         // We'll start heartbeat process on session start.
         self.hb(ctx);
+        ctx.run_interval(Duration::new(ACK_CHECK_SEC, 0), |act, _| act.redeliver());
+        ctx.run_interval(Duration::from_millis(OUTBOUND_FLUSH_MS), |act, _| {
+            act.flush_outbound()
+        });
         println!("Session started");
 
         // Register self in MQ server. `AsyncContext::wait` register
@@ -76,18 +136,116 @@ impl Actor for MqSession {
 
     fn stopping(&mut self, _: &mut Self::Context) -> Running {
         // notify MQ server
-        self.addr.do_send(server::Disconnect(self.pub_key.unwrap()));
+        let pub_key = self.pub_key.unwrap();
+        // Rate limiter is kept by MQ server until client reconnected
+        if self.registered {
+            let limiter = mem::replace(
+                &mut self.limiter,
+                RateLimiter::new(&self.settings.rate_limit.default_limit()),
+            );
+            self.addr
+                .do_send(server::MqRateLimiter { pub_key, limiter });
+        }
+        self.addr.do_send(server::Disconnect(pub_key));
+
+        // Not acknowledged and not sent messages
+        // will be redelivered when client reconnected
+        if !self.in_flight.is_empty() || !self.outbound.is_empty() {
+            let mut in_flight: Vec<InFlight> = self.in_flight.drain().map(|(_, m)| m).collect();
+            in_flight.sort_by_key(|m| m.offset);
+            let messages = in_flight
+                .into_iter()
+                .map(|mut m| {
+                    m.message.redelivered += 1;
+                    m.message
+                })
+                .chain(self.outbound.drain(..))
+                .collect();
+            self.addr.do_send(server::MqRequeue {
+                to: pub_key,
+                messages,
+            });
+        }
         Running::Stop
     }
 }
 
-impl WriteHandler<io::Error> for MqSession {}
+impl WriteHandler<io::Error> for MqSession {
+    fn error(&mut self, err: io::Error, _: &mut Self::Context) -> Running {
+        eprintln!("Session write error: {}", err);
+        Running::Stop
+    }
+}
 
 /// To use `Framed` with an actor, we have to implement `StreamHandler` trait
 impl StreamHandler<MqRequest, io::Error> for MqSession {
+    /// Peer sends invalid frame, connection can't be used anymore
+    fn error(&mut self, err: io::Error, _: &mut Self::Context) -> Running {
+        eprintln!("Session stream error: {}", err);
+        Running::Stop
+    }
+
     /// This is main event loop for client requests
     fn handle(&mut self, msg: MqRequest, ctx: &mut Self::Context) {
+        // Hello should be first request for session
+        match (&msg, self.capabilities.is_some()) {
+            (MqRequest::Hello(_), true) => return self.reject(MqError::UnexpectedHello),
+            (MqRequest::Hello(_), false) => {}
+            (_, false) => return self.reject(MqError::HelloRequired),
+            _ => {}
+        }
+
+        // Requests forwarded to MQ server are rate limited
+        // by count and message body bytes
+        let size = match msg {
+            MqRequest::Message(ref message) => Some(message.body.0.len() as u64),
+            MqRequest::Hello(_)
+            | MqRequest::Ping
+            | MqRequest::Register(_)
+            | MqRequest::RegisterProof(_)
+            | MqRequest::Ack(..)
+            | MqRequest::Disconnect => None,
+            _ => Some(0),
+        };
+        if let Some(size) = size {
+            if !self.limiter.check(size) {
+                return self.rate_limited(msg);
+            }
+        }
+
         match msg {
+            MqRequest::Hello(hello) => {
+                if hello.version != PROTOCOL_VERSION {
+                    eprintln!("Hello: unsupported protocol version {}", hello.version);
+                    self.reject(MqError::UnsupportedVersion(PROTOCOL_VERSION));
+                    return;
+                }
+
+                // Enable capabilities supported by both peers
+                let capabilities: Vec<Capability> = hello
+                    .capabilities
+                    .into_iter()
+                    .filter(|c| NODE_CAPABILITIES.contains(c))
+                    .collect();
+                // Select most preferred by client format supported
+                // by Node, Json is used if there is no such format
+                let format = hello
+                    .formats
+                    .into_iter()
+                    .find(|f| f.is_supported())
+                    .unwrap_or_default();
+                println!("Hello: {:?}, {:?}", capabilities, format);
+
+                // Response is sent with current format, after that
+                // all frames will be sent with selected format
+                self.framed.write(MqResponse::Hello(HelloAck {
+                    version: PROTOCOL_VERSION,
+                    capabilities: capabilities.clone(),
+                    format,
+                }));
+                self.format.set(format);
+                self.capabilities = Some(capabilities);
+            }
             MqRequest::Message(message) => {
                 if self.pub_key.is_none() {
                     eprintln!("MqRequest::Message - pub_key not sets");
@@ -120,36 +278,172 @@ impl StreamHandler<MqRequest, io::Error> for MqSession {
                     eprintln!("Register pub_key: session pub_key not set");
                     return;
                 }
-
-                let old_pub_key = self.pub_key.unwrap();
+                // Session registers only one client pub_key
+                if self.registered {
+                    eprintln!("Register pub_key: session already registered");
+                    self.reject(MqError::AlreadyRegistered);
+                    return;
+                }
 
                 println!("Register pub_key: {}", sign::to_hex_pk(&pk));
 
+                // Client should prove key ownership
+                // before pub_key will be registered
+                let challenge = RegisterChallenge {
+                    node: self.settings.public_key,
+                    nonce: sign::gen_challenge_nonce(),
+                };
+                self.framed
+                    .write(MqResponse::RegisterChallenge(challenge.clone()));
+                self.challenge = Some((pk, challenge));
+            }
+            MqRequest::RegisterProof(signature) => {
+                if self.registered {
+                    eprintln!("Register proof: session already registered");
+                    self.reject(MqError::AlreadyRegistered);
+                    return;
+                }
+                let (pk, challenge) = match self.challenge.take() {
+                    Some(challenge) => challenge,
+                    None => {
+                        eprintln!("Register proof: challenge not found");
+                        self.reject(MqError::ChallengeNotFound);
+                        return;
+                    }
+                };
+
+                if !sign::verify(&signature, &challenge.sign_data(&pk), &pk) {
+                    eprintln!("Register proof: invalid signature");
+                    self.reject(MqError::InvalidSignature);
+                    return;
+                }
+
+                let old_pub_key = self.pub_key.unwrap();
+
                 self.addr
                     .send(server::MqRegister {
-                        old_pub_key: old_pub_key,
+                        old_pub_key,
                         pub_key: pk,
                     })
                     .into_actor(self)
                     .then(|res, act, ctx| {
                         match res {
                             // Registration successful
-                            Ok(Some(pub_key)) => {
+                            Ok(Ok((pub_key, limiter))) => {
                                 // Change old pub_key
-                                act.pub_key = Some(pub_key.clone());
+                                act.pub_key = Some(pub_key);
+                                act.limiter = limiter;
+                                act.registered = true;
+                                act.framed.write(MqResponse::Registered(pub_key));
                             }
                             // Registration failed
-                            // stopping current session
+                            // closing current session
+                            Ok(Err(err)) => act.reject(err),
+                            _ => ctx.stop(),
+                        }
+                        actix::fut::ok(())
+                    })
+                    .wait(ctx);
+            }
+            MqRequest::PublishKey(key) => {
+                if !self.is_enabled(Capability::KeyDirectory) {
+                    self.reject(MqError::CapabilityNotEnabled(Capability::KeyDirectory));
+                    return;
+                }
+                let pub_key = self.pub_key.unwrap();
+                // Only key owner can publish box_ PublicKey
+                if !key.verify(&pub_key) {
+                    eprintln!("Publish key: invalid signature");
+                    self.reject(MqError::InvalidSignature);
+                    return;
+                }
+                self.addr.do_send(server::MqPublishKey { pub_key, key });
+            }
+            MqRequest::LookupKey(pk) => {
+                if !self.is_enabled(Capability::KeyDirectory) {
+                    self.reject(MqError::CapabilityNotEnabled(Capability::KeyDirectory));
+                    return;
+                }
+                self.addr
+                    .send(server::MqLookupKey(pk))
+                    .into_actor(self)
+                    .then(move |res, act, ctx| {
+                        match res {
+                            Ok(key) => act.framed.write(MqResponse::BoxKey(pk, key)),
+                            // something is wrong with MQ server
                             _ => ctx.stop(),
                         }
                         actix::fut::ok(())
                     })
                     .wait(ctx);
             }
+            MqRequest::Ack(from, id) => {
+                if !self.is_enabled(Capability::Acks) {
+                    self.reject(MqError::CapabilityNotEnabled(Capability::Acks));
+                    return;
+                }
+                // Acknowledged message can wait for redelivery
+                self.outbound
+                    .retain(|m| m.redelivered == 0 || m.from != from || m.id != id);
+                self.in_flight.remove(&(from, id.clone()));
+                self.acked(from, id);
+            }
+            MqRequest::LastWill(will) => {
+                if !self.is_enabled(Capability::LastWill) {
+                    self.reject(MqError::CapabilityNotEnabled(Capability::LastWill));
+                    return;
+                }
+                let pub_key = self.pub_key.unwrap();
+                self.addr.do_send(server::MqLastWill {
+                    pub_key,
+                    message: will.map(|will| will.to_message(&pub_key)),
+                });
+            }
+            MqRequest::Presence(keys) => {
+                if !self.is_enabled(Capability::Presence) {
+                    self.reject(MqError::CapabilityNotEnabled(Capability::Presence));
+                    return;
+                }
+                self.presence(server::MqPresence(keys), ctx);
+            }
+            MqRequest::PresenceSub(keys) => {
+                if !self.is_enabled(Capability::Presence) {
+                    self.reject(MqError::CapabilityNotEnabled(Capability::Presence));
+                    return;
+                }
+                let watcher = self.pub_key.unwrap();
+                self.presence(server::MqPresenceSub { watcher, keys }, ctx);
+            }
+            MqRequest::PresenceUnSub(keys) => {
+                if !self.is_enabled(Capability::Presence) {
+                    self.reject(MqError::CapabilityNotEnabled(Capability::Presence));
+                    return;
+                }
+                self.addr.do_send(server::MqPresenceUnSub {
+                    watcher: self.pub_key.unwrap(),
+                    keys,
+                });
+            }
+            MqRequest::Disconnect => {
+                println!("MqRequest::Disconnect");
+                // Clear last-will message before session is closed
+                self.addr.do_send(server::MqLastWill {
+                    pub_key: self.pub_key.unwrap(),
+                    message: None,
+                });
+                ctx.stop();
+            }
             MqRequest::MessageResponse(response) => {
+                // Only message recipient can report message status
+                if response.to != self.pub_key {
+                    eprintln!("Message response: not message recipient");
+                    self.reject(MqError::NotRecipient);
+                    return;
+                }
                 self.addr.do_send(server::MqMessageResponse {
                     from: response.from,
                     to: response.to,
+                    id: response.id,
                     status: response.status,
                 });
             }
@@ -161,9 +455,37 @@ impl StreamHandler<MqRequest, io::Error> for MqSession {
 impl Handler<MqSessionMessage> for MqSession {
     type Result = ();
 
-    fn handle(&mut self, msg: MqSessionMessage, _: &mut Self::Context) {
-        // Send message to peer
-        self.framed.write(MqResponse::Message(msg.0));
+    fn handle(&mut self, msg: MqSessionMessage, ctx: &mut Self::Context) {
+        let cfg = &self.settings.outbound;
+        // Slow peer outbound queue is full
+        if self.outbound.len() >= cfg.capacity {
+            match cfg.policy {
+                SlowConsumerPolicy::DropOldest => {
+                    if let Some(dropped) = self.outbound.pop_front() {
+                        self.dropped(dropped);
+                    }
+                }
+                SlowConsumerPolicy::DropNewest => return self.dropped(msg.0),
+                SlowConsumerPolicy::Disconnect => {
+                    println!("Slow consumer, disconnecting");
+                    // Queued messages are requeued when session stopping
+                    self.outbound.push_back(msg.0);
+                    ctx.stop();
+                    return;
+                }
+            }
+        }
+        self.outbound.push_back(msg.0);
+        self.flush_outbound();
+    }
+}
+
+/// Handler for watched client presence change
+impl Handler<MqSessionPresence> for MqSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: MqSessionPresence, _: &mut Self::Context) {
+        self.framed.write(MqResponse::PresenceChanged(msg.0));
     }
 }
 
@@ -173,10 +495,7 @@ impl Handler<MqSessionDisconnect> for MqSession {
 
     fn handle(&mut self, _: MqSessionDisconnect, ctx: &mut Self::Context) {
         println!("Handler<MqSessionDisconnect>");
-        // Notify MQ server
-        self.addr.do_send(server::Disconnect(self.pub_key.unwrap()));
-
-        // Stop actor
+        // Stop actor, MQ server is notified when actor stopping
         ctx.stop();
     }
 }
@@ -207,14 +526,210 @@ impl MqSession {
     /// Basic Session initialisation
     pub fn new(
         addr: Addr<MqServer>,
-        framed: FramedWrite<WriteHalf<TcpStream>, MqCodec>,
+        mut framed: FramedWrite<CountingWrite<WriteHalf<TcpStream>>, MqCodec>,
+        settings: NodeAppConfig,
+        format: WireFormatHandle,
+        encoded: ByteCounter,
+        written: ByteCounter,
     ) -> MqSession {
+        // Write buffer is limited by outbound window,
+        // so full buffer should not block session
+        framed.set_buffer_capacity(settings.outbound.window_size as usize, usize::MAX);
+        // Session pub_key is temporary until registration
+        let limiter = RateLimiter::new(&settings.rate_limit.default_limit());
         MqSession {
             pub_key: None,
             addr,
             framed,
             hb: Instant::now(),
+            settings,
+            challenge: None,
+            format,
+            capabilities: None,
+            in_flight: HashMap::new(),
+            outbound: VecDeque::new(),
+            encoded,
+            written,
+            limiter,
+            registered: false,
+        }
+    }
+
+    /// Check is capability enabled for session
+    fn is_enabled(&self, capability: Capability) -> bool {
+        self.capabilities.iter().flatten().any(|c| *c == capability)
+    }
+
+    /// Send queued messages to peer until
+    /// outbound window is exceeded
+    fn flush_outbound(&mut self) {
+        while self.backlog() < self.settings.outbound.window_size {
+            match self.outbound.pop_front() {
+                Some(msg) => self.send_message(msg),
+                None => break,
+            }
+        }
+    }
+
+    /// Bytes written to write buffer and not sent yet
+    fn backlog(&self) -> u64 {
+        self.encoded.get().saturating_sub(self.written.get())
+    }
+
+    /// Notify peer about request dropped by rate limit
+    fn rate_limited(&mut self, msg: MqRequest) {
+        match msg {
+            MqRequest::Message(message) => {
+                println!("Rate limit exceeded, message dropped: {}", message.id);
+                self.framed.write(MqResponse::MessageResponseStatus(
+                    server::MqMessageResponse {
+                        from: self.pub_key.unwrap(),
+                        to: message.to,
+                        id: message.id,
+                        status: server::MessageSendStatus::RateLimited,
+                    },
+                ));
+            }
+            _ => println!("Rate limit exceeded, request dropped"),
+        }
+    }
+
+    /// Notify sender about message dropped by full outbound queue
+    fn dropped(&self, msg: server::MqMessage) {
+        println!("Outbound queue full, message dropped: {}", msg.id);
+        self.acked(msg.from, msg.id.clone());
+        self.addr.do_send(server::MqMessageResponse {
+            from: msg.from,
+            to: self.pub_key,
+            id: msg.id,
+            status: server::MessageSendStatus::Dropped,
+        });
+    }
+
+    /// Notify MQ server that message is done and
+    /// not needed for redelivery after Node restart
+    fn acked(&self, from: PublicKey, id: String) {
+        self.addr.do_send(server::MqAcked {
+            to: self.pub_key.unwrap(),
+            from,
+            id,
+        });
+    }
+
+    /// Write message to peer connection
+    fn send_message(&mut self, msg: server::MqMessage) {
+        let status = if msg.is_expired(SystemTime::now()) {
+            server::MessageSendStatus::Expired
+        } else {
+            server::MessageSendStatus::Delivered
+        };
+        // Notify sender about REQ/REP message delivery,
+        // redelivered message was already reported
+        if msg.to.is_some()
+            && (msg.redelivered == 0 || status != server::MessageSendStatus::Delivered)
+        {
+            self.addr.do_send(server::MqMessageResponse {
+                from: msg.from,
+                to: msg.to,
+                id: msg.id.clone(),
+                status: status.clone(),
+            });
+        }
+        // Expired message is discarded
+        if status == server::MessageSendStatus::Expired {
+            return self.acked(msg.from, msg.id);
+        }
+        let key = (msg.from, msg.id.clone());
+        let message = if self.is_enabled(Capability::Acks) {
+            Some(msg.clone())
+        } else {
+            // Message is done when written without acknowledgement
+            self.acked(msg.from, msg.id.clone());
+            None
+        };
+        // Send message to peer
+        self.framed.write(MqResponse::Message(Box::new(msg)));
+        // Wait for message acknowledgement, ack timeout
+        // starts when message is sent from write buffer
+        if let Some(message) = message {
+            self.in_flight.insert(
+                key,
+                InFlight {
+                    offset: self.encoded.get(),
+                    sent: None,
+                    message,
+                },
+            );
+        }
+    }
+
+    /// Requeue messages not acknowledged during ack timeout
+    /// to outbound queue for redelivery
+    fn redeliver(&mut self) {
+        let timeout = Duration::new(self.settings.ack_timeout_sec, 0);
+        let now = Instant::now();
+        let written = self.written.get();
+        // Expired messages are not redelivered
+        let expired_at = SystemTime::now();
+        let expired: Vec<(PublicKey, String)> = self
+            .in_flight
+            .iter()
+            .filter(|(_, in_flight)| in_flight.message.is_expired(expired_at))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for (from, id) in expired {
+            self.in_flight.remove(&(from, id.clone()));
+            self.acked(from, id);
+        }
+
+        let mut timed_out = vec![];
+        for (key, in_flight) in self.in_flight.iter_mut() {
+            match in_flight.sent {
+                Some(sent) if now.duration_since(sent) > timeout => timed_out.push(key.clone()),
+                Some(_) => {}
+                None if written >= in_flight.offset => in_flight.sent = Some(now),
+                None => {}
+            }
         }
+        for key in timed_out {
+            if let Some(mut in_flight) = self.in_flight.remove(&key) {
+                in_flight.message.redelivered += 1;
+                println!(
+                    "Redeliver message: {} ({})",
+                    in_flight.message.id, in_flight.message.redelivered
+                );
+                self.outbound.push_back(in_flight.message);
+            }
+        }
+        self.flush_outbound();
+    }
+
+    /// Send presence request to MQ server and
+    /// send clients presence to peer
+    fn presence<M>(&mut self, msg: M, ctx: &mut actix::Context<Self>)
+    where
+        M: actix::Message<Result = Vec<PresenceStatus>> + Send + 'static,
+        MqServer: Handler<M>,
+    {
+        self.addr
+            .send(msg)
+            .into_actor(self)
+            .then(|res, act, ctx| {
+                match res {
+                    Ok(statuses) => act.framed.write(MqResponse::Presence(statuses)),
+                    // something is wrong with MQ server
+                    _ => ctx.stop(),
+                }
+                actix::fut::ok(())
+            })
+            .wait(ctx);
+    }
+
+    /// Send error response to peer and close session
+    /// when all pending data is written.
+    fn reject(&mut self, err: MqError) {
+        self.framed.write(MqResponse::Error(err));
+        self.framed.close();
     }
 
     /// Helper method that sends ping to client every second.
@@ -227,11 +742,9 @@ impl MqSession {
                 // Heartbeat timed out
                 println!("Client heartbeat failed, disconnecting!");
 
-                // Notify MQ server
-                act.addr.do_send(server::Disconnect(act.pub_key.unwrap()));
-
-                // Stop actor
+                // Stop actor, MQ server is notified when actor stopping
                 ctx.stop();
+                return;
             }
 
             act.framed.write(MqResponse::Pong);
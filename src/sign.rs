@@ -1,10 +1,16 @@
 #![allow(dead_code)]
+use crate::codec::{MessageData, MessageProtocol};
 use hex;
 use sodiumoxide::crypto::{
     box_,
     sign::ed25519,
     sign::ed25519::{PublicKey, SecretKey, Seed, Signature},
 };
+use sodiumoxide::randombytes;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Register challenge nonce size
+pub const CHALLENGE_NONCE_BYTES: usize = 32;
 
 /// Initializes the sodium library and automatically selects faster versions
 /// of the primitives, if possible.
@@ -36,12 +42,200 @@ pub fn gen_box_keypair() -> (box_::PublicKey, box_::SecretKey) {
     box_::gen_keypair()
 }
 
+/// Generate random nonce for register challenge
+pub fn gen_challenge_nonce() -> Vec<u8> {
+    randombytes::randombytes(CHALLENGE_NONCE_BYTES)
+}
+
 /// Verifies that `data` is signed with a secret key corresponding to the
 /// given public key.
 pub fn verify(sig: &Signature, data: &[u8], pub_key: &PublicKey) -> bool {
     ed25519::verify_detached(sig, data, pub_key)
 }
 
+/// Domain separation tag for message signing payload
+pub const MESSAGE_PAYLOAD_TAG: &[u8] = b"amq-message-v1";
+
+/// Returns canonical message signing payload.
+///
+/// Payload is deterministic byte encoding of signed `MessageData` fields
+/// and the sender `from` key. Fields are written in the following order,
+/// integers are big-endian, optional fields are prefixed with presence
+/// byte `0x00` (none) or `0x01` (some), and variable-length fields are
+/// prefixed with `u32` length:
+///
+/// | Field          | Encoding                                             |
+/// |----------------|------------------------------------------------------|
+/// | tag            | `MESSAGE_PAYLOAD_TAG` bytes                          |
+/// | `id`           | `u32` length + UTF-8 bytes                           |
+/// | `to`           | presence + 32 bytes PublicKey                        |
+/// | `from`         | 32 bytes PublicKey                                   |
+/// | `event`        | presence + `u32` length + UTF-8 bytes                |
+/// | `protocol`     | `u8`: Pub = 0, Sub = 1, UnSub = 2, ReqRep = 3,       |
+/// |                | Reply = 4                                            |
+/// |                | Pub is followed by `u8` retain flag                  |
+/// |                | Sub is followed by `u8` durable flag and `group`:    |
+/// |                | presence + `u32` length + UTF-8 bytes                |
+/// |                | Reply is followed by `correlation_id`:               |
+/// |                | `u32` length + UTF-8 bytes                           |
+/// | `time`         | `u64` seconds + `u32` nanoseconds since UNIX epoch   |
+/// | `nonce`        | presence + 24 bytes Nonce                            |
+/// | `body`         | `u32` length + bytes                                 |
+/// | `content_type` | presence + `u32` length + UTF-8 bytes                |
+/// | `expires_at`   | presence + `u64` seconds + `u32` nanoseconds         |
+/// | `deliver_at`   | presence + `u64` seconds + `u32` nanoseconds         |
+///
+/// Time before UNIX epoch is encoded as zero.
+///
+/// # Test vectors
+///
+/// ```
+/// use amq::codec::{MessageData, MessageProtocol};
+/// use amq::sign;
+/// use sodiumoxide::crypto::sign::ed25519::{PublicKey, Seed};
+/// use std::time::{Duration, UNIX_EPOCH};
+///
+/// let (from, secret_key) = sign::gen_keypair_from_seed(&Seed([1; 32]));
+/// let mut msg = MessageData {
+///     id: "id".to_string(),
+///     to: Some(PublicKey([2; 32])),
+///     signature: None,
+///     event: Some("ev".to_string()),
+///     protocol: MessageProtocol::ReqRep,
+///     time: UNIX_EPOCH + Duration::new(1, 2),
+///     nonce: None,
+///     body: "body".into(),
+///     content_type: Some("text/plain".to_string()),
+///     expires_at: Some(UNIX_EPOCH + Duration::new(3, 4)),
+///     deliver_at: None,
+/// };
+///
+/// let payload = sign::message_payload(&msg, &PublicKey([3; 32]));
+/// assert_eq!(
+///     sign::to_hex(&payload),
+///     [
+///         "616d712d6d6573736167652d7631",
+///         "00000002" /* id */, "6964",
+///         "01" /* to */, &"02".repeat(32),
+///         /* from */ &"03".repeat(32),
+///         "01" /* event */, "00000002", "6576",
+///         "03" /* protocol */,
+///         "0000000000000001" /* time */, "00000002",
+///         "00" /* nonce */,
+///         "00000004" /* body */, "626f6479",
+///         "01" /* content_type */, "0000000a", "746578742f706c61696e",
+///         "01" /* expires_at */, "0000000000000003", "00000004",
+///         "00" /* deliver_at */,
+///     ]
+///     .concat()
+/// );
+///
+/// let signature = sign::sign_message(&msg, &from, &secret_key);
+/// assert_eq!(
+///     sign::to_hex(&signature[..]),
+///     "5601d9d12167b946ab01ed691f287cbbacf062a1da383b226ed91f5309b87ea8\
+///      a13a8dc056d1b28750dd199b972f2bdb201924b0885b395ef8a045ca0e52400a"
+/// );
+/// msg.signature = Some(signature);
+/// assert!(sign::verify_message(&msg, &from));
+/// ```
+pub fn message_payload(msg: &MessageData, from: &PublicKey) -> Vec<u8> {
+    let mut data = Vec::from(MESSAGE_PAYLOAD_TAG);
+    put_bytes(&mut data, msg.id.as_bytes());
+    match msg.to {
+        Some(to) => {
+            data.push(1);
+            data.extend_from_slice(&to[..]);
+        }
+        None => data.push(0),
+    }
+    data.extend_from_slice(&from[..]);
+    match msg.event {
+        Some(ref event) => {
+            data.push(1);
+            put_bytes(&mut data, event.as_bytes());
+        }
+        None => data.push(0),
+    }
+    match msg.protocol {
+        MessageProtocol::Pub { retain } => {
+            data.push(0);
+            data.push(retain as u8);
+        }
+        MessageProtocol::Sub { durable, ref group } => {
+            data.push(1);
+            data.push(durable as u8);
+            match group {
+                Some(group) => {
+                    data.push(1);
+                    put_bytes(&mut data, group.as_bytes());
+                }
+                None => data.push(0),
+            }
+        }
+        MessageProtocol::UnSub => data.push(2),
+        MessageProtocol::ReqRep => data.push(3),
+        MessageProtocol::Reply { ref correlation_id } => {
+            data.push(4);
+            put_bytes(&mut data, correlation_id.as_bytes());
+        }
+    }
+    put_time(&mut data, msg.time);
+    match msg.nonce {
+        Some(nonce) => {
+            data.push(1);
+            data.extend_from_slice(&nonce[..]);
+        }
+        None => data.push(0),
+    }
+    put_bytes(&mut data, msg.body.as_bytes());
+    match msg.content_type {
+        Some(ref content_type) => {
+            data.push(1);
+            put_bytes(&mut data, content_type.as_bytes());
+        }
+        None => data.push(0),
+    }
+    for time in [msg.expires_at, msg.deliver_at].iter() {
+        match time {
+            Some(time) => {
+                data.push(1);
+                put_time(&mut data, *time);
+            }
+            None => data.push(0),
+        }
+    }
+    data
+}
+
+/// Append time as `u64` seconds and `u32` nanoseconds since UNIX epoch
+fn put_time(data: &mut Vec<u8>, time: SystemTime) {
+    let time = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    data.extend_from_slice(&time.as_secs().to_be_bytes());
+    data.extend_from_slice(&time.subsec_nanos().to_be_bytes());
+}
+
+/// Append `u32` length prefixed bytes
+fn put_bytes(data: &mut Vec<u8>, bytes: &[u8]) {
+    data.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+    data.extend_from_slice(bytes);
+}
+
+/// Signs message canonical payload with sender secret key.
+/// Message `signature` field is not signed.
+pub fn sign_message(msg: &MessageData, from: &PublicKey, secret_key: &SecretKey) -> Signature {
+    sign(&message_payload(msg, from), secret_key)
+}
+
+/// Verifies message signature over canonical payload
+/// with sender public key. Unsigned message is not verified.
+pub fn verify_message(msg: &MessageData, from: &PublicKey) -> bool {
+    match msg.signature {
+        Some(ref sig) => verify(sig, &message_payload(msg, from), from),
+        None => false,
+    }
+}
+
 /// Returns a hex representation of binary data.
 pub fn to_hex_pk(pk: &PublicKey) -> String {
     hex::encode(&pk[..])
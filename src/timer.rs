@@ -0,0 +1,151 @@
+use std::mem;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Hashed timer wheel for delayed items.
+///
+/// Time is split to ticks, item due at tick `t` is stored in slot
+/// `t % slots`. Wheel advance visits only slots of passed ticks, items
+/// due in later wheel rotations are kept in slot until their tick.
+#[derive(Debug)]
+pub struct TimerWheel<T> {
+    /// Tick duration in milliseconds
+    tick_ms: u64,
+    slots: Vec<Vec<(u64, T)>>,
+    /// Last processed tick
+    current: u64,
+    /// Items count
+    len: usize,
+}
+
+impl<T> TimerWheel<T> {
+    pub fn new(tick: Duration, slots: usize) -> TimerWheel<T> {
+        let mut wheel = TimerWheel {
+            tick_ms: (tick.as_millis() as u64).max(1),
+            slots: (0..slots.max(1)).map(|_| vec![]).collect(),
+            current: 0,
+            len: 0,
+        };
+        wheel.current = wheel.tick(SystemTime::now());
+        wheel
+    }
+
+    /// Add item due at time. Item with due time
+    /// in the past is returned by next advance.
+    pub fn insert(&mut self, due: SystemTime, item: T) {
+        let tick = self.tick(due).max(self.current + 1);
+        let slot = self.slot(tick);
+        self.slots[slot].push((tick, item));
+        self.len += 1;
+    }
+
+    /// Advance wheel to time, returns due items
+    pub fn advance(&mut self, now: SystemTime) -> Vec<T> {
+        let target = self.tick(now);
+        let mut due = vec![];
+        if target <= self.current {
+            return due;
+        }
+        // One wheel rotation visits every slot
+        let steps = (target - self.current).min(self.slots.len() as u64);
+        for step in 1..=steps {
+            let slot = self.slot(self.current + step);
+            for (tick, item) in mem::take(&mut self.slots[slot]) {
+                if tick <= target {
+                    due.push(item);
+                } else {
+                    self.slots[slot].push((tick, item));
+                }
+            }
+        }
+        self.current = target;
+        self.len -= due.len();
+        due
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Tick number of time
+    fn tick(&self, time: SystemTime) -> u64 {
+        let time = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        time.as_millis() as u64 / self.tick_ms
+    }
+
+    /// Slot index of tick
+    fn slot(&self, tick: u64) -> usize {
+        (tick % self.slots.len() as u64) as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TICK_MS: u64 = 10;
+    const SLOTS: usize = 8;
+
+    /// Wheel and start time of its current tick
+    fn wheel() -> (TimerWheel<u32>, SystemTime) {
+        let wheel = TimerWheel::new(Duration::from_millis(TICK_MS), SLOTS);
+        let start = UNIX_EPOCH + Duration::from_millis(wheel.current * TICK_MS);
+        (wheel, start)
+    }
+
+    fn ticks(start: SystemTime, ticks: u64) -> SystemTime {
+        start + Duration::from_millis(ticks * TICK_MS)
+    }
+
+    #[test]
+    fn past_due_item_returned_by_next_advance() {
+        let (mut wheel, start) = wheel();
+        wheel.insert(start - Duration::from_secs(5), 1);
+        wheel.insert(start, 2);
+        assert_eq!(wheel.len(), 2);
+        // Current tick is already processed
+        assert!(wheel.advance(start).is_empty());
+
+        let mut due = wheel.advance(ticks(start, 1));
+        due.sort();
+        assert_eq!(due, vec![1, 2]);
+        assert!(wheel.is_empty());
+    }
+
+    #[test]
+    fn item_due_after_several_rotations() {
+        let (mut wheel, start) = wheel();
+        let due_tick = SLOTS as u64 * 2 + 4;
+        wheel.insert(ticks(start, due_tick), 1);
+        // Item slot is visited in every rotation before due tick
+        for tick in 1..due_tick {
+            assert!(wheel.advance(ticks(start, tick)).is_empty(), "{}", tick);
+        }
+        assert_eq!(wheel.len(), 1);
+        assert_eq!(wheel.advance(ticks(start, due_tick)), vec![1]);
+        assert!(wheel.is_empty());
+    }
+
+    #[test]
+    fn advance_more_than_rotation() {
+        let (mut wheel, start) = wheel();
+        wheel.insert(ticks(start, 3), 1);
+        wheel.insert(ticks(start, SLOTS as u64 + 4), 2);
+        wheel.insert(ticks(start, SLOTS as u64 * 4), 3);
+
+        let mut due = wheel.advance(ticks(start, SLOTS as u64 * 3));
+        due.sort();
+        assert_eq!(due, vec![1, 2]);
+        assert_eq!(wheel.len(), 1);
+
+        // Item inserted after jump is due relative to new current tick
+        wheel.insert(ticks(start, SLOTS as u64 * 3 + 1), 4);
+        let mut due = wheel.advance(ticks(start, SLOTS as u64 * 5));
+        due.sort();
+        assert_eq!(due, vec![3, 4]);
+        assert!(wheel.is_empty());
+    }
+}
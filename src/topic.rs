@@ -0,0 +1,340 @@
+use sodiumoxide::crypto::sign::ed25519::PublicKey;
+use std::cell::Cell;
+use std::collections::HashMap;
+
+/// Event name segments separator
+pub const SEGMENT_SEPARATOR: char = '.';
+/// Pattern segment that matches exactly one segment
+pub const SINGLE_WILDCARD: &str = "*";
+/// Pattern segments that match one or more last segments
+pub const MULTI_WILDCARDS: &[&str] = &[">", "#"];
+
+/// Check is event name valid for PUB: non-empty
+/// segments without wildcards.
+pub fn is_valid_name(name: &str) -> bool {
+    name.split(SEGMENT_SEPARATOR)
+        .all(|segment| !segment.is_empty() && !is_wildcard(segment))
+}
+
+/// Check is subscription pattern valid: non-empty segments,
+/// multi-segment wildcard can be only last segment.
+pub fn is_valid_pattern(pattern: &str) -> bool {
+    let segments: Vec<&str> = pattern.split(SEGMENT_SEPARATOR).collect();
+    let last = segments.len() - 1;
+    segments.iter().enumerate().all(|(i, segment)| {
+        !segment.is_empty() && (i == last || !MULTI_WILDCARDS.contains(segment))
+    })
+}
+
+/// Check is event name matches subscription pattern
+pub fn is_match(pattern: &str, name: &str) -> bool {
+    let mut names = name.split(SEGMENT_SEPARATOR);
+    for segment in pattern.split(SEGMENT_SEPARATOR) {
+        if MULTI_WILDCARDS.contains(&segment) {
+            return names.next().is_some();
+        }
+        match names.next() {
+            Some(name) if segment == SINGLE_WILDCARD || segment == name => {}
+            _ => return false,
+        }
+    }
+    names.next().is_none()
+}
+
+/// Check is pattern segment wildcard
+fn is_wildcard(segment: &str) -> bool {
+    segment == SINGLE_WILDCARD || MULTI_WILDCARDS.contains(&segment)
+}
+
+/// Event subscribers trie.
+///
+/// Subscription patterns are split to segments by `.`, every segment
+/// is trie node. Patterns can contain wildcards: `*` matches exactly one
+/// segment, `>` and `#` match one or more last segments. For example
+/// `a.*.c` matches `a.b.c`, and `a.>` matches `a.b` and `a.b.c`.
+/// PUB routing walks only trie nodes matching event name segments.
+#[derive(Debug, Default)]
+pub struct TopicTrie {
+    root: TopicNode,
+}
+
+/// Topic trie node
+#[derive(Debug, Default)]
+struct TopicNode {
+    children: HashMap<String, TopicNode>,
+    /// Full pattern of node, set if node has subscribers
+    pattern: String,
+    /// Subscribers receiving every message
+    subscribers: Vec<PublicKey>,
+    /// Queue groups by group name
+    groups: HashMap<String, QueueGroup>,
+}
+
+/// Subscribers of pattern matching event name
+#[derive(Debug)]
+pub struct TopicMatch<'a> {
+    pub pattern: &'a str,
+    /// Subscribers receiving every message
+    pub subscribers: &'a [PublicKey],
+    /// Queue groups, message is delivered to one member of group
+    pub groups: Vec<&'a QueueGroup>,
+}
+
+/// Queue group - competing subscribers
+/// receiving messages round-robin.
+#[derive(Debug, Default)]
+pub struct QueueGroup {
+    members: Vec<PublicKey>,
+    /// Next member index
+    next: Cell<usize>,
+}
+
+impl QueueGroup {
+    /// Select next member accepted by filter, round-robin
+    pub fn next_member<F>(&self, filter: F) -> Option<PublicKey>
+    where
+        F: Fn(&PublicKey) -> bool,
+    {
+        for _ in 0..self.members.len() {
+            let index = self.next.get() % self.members.len();
+            self.next.set(index + 1);
+            if filter(&self.members[index]) {
+                return Some(self.members[index]);
+            }
+        }
+        None
+    }
+}
+
+impl TopicTrie {
+    /// Add pattern subscriber, subscriber with group is added to
+    /// queue group. Returns false if subscriber already subscribed.
+    pub fn insert(&mut self, pattern: &str, pub_key: PublicKey, group: Option<&str>) -> bool {
+        let mut node = &mut self.root;
+        for segment in pattern.split(SEGMENT_SEPARATOR) {
+            node = node.children.entry(segment.to_owned()).or_default();
+        }
+        node.pattern = pattern.to_owned();
+        let subscribers = match group {
+            Some(group) => &mut node.groups.entry(group.to_owned()).or_default().members,
+            None => &mut node.subscribers,
+        };
+        if subscribers.contains(&pub_key) {
+            return false;
+        }
+        subscribers.push(pub_key);
+        true
+    }
+
+    /// Remove pattern subscriber, trie nodes
+    /// without subscribers are removed.
+    pub fn remove(&mut self, pattern: &str, pub_key: &PublicKey, group: Option<&str>) -> bool {
+        let segments: Vec<&str> = pattern.split(SEGMENT_SEPARATOR).collect();
+        self.root.remove(&segments, pub_key, group)
+    }
+
+    /// Subscribers of patterns matching event name,
+    /// grouped by matched pattern.
+    pub fn matches(&self, name: &str) -> Vec<TopicMatch<'_>> {
+        let segments: Vec<&str> = name.split(SEGMENT_SEPARATOR).collect();
+        let mut matched = vec![];
+        self.root.matches(&segments, &mut matched);
+        matched
+    }
+
+    /// Check is trie has no subscribers
+    pub fn is_empty(&self) -> bool {
+        self.root.children.is_empty()
+    }
+}
+
+impl TopicNode {
+    fn remove(&mut self, segments: &[&str], pub_key: &PublicKey, group: Option<&str>) -> bool {
+        let (segment, rest) = match segments.split_first() {
+            Some(split) => split,
+            None => {
+                let subscribers = match group {
+                    Some(group) => match self.groups.get_mut(group) {
+                        Some(group) => &mut group.members,
+                        None => return false,
+                    },
+                    None => &mut self.subscribers,
+                };
+                let element = subscribers.iter().position(|x| x == pub_key);
+                if let Some(element) = element {
+                    let _ = subscribers.remove(element);
+                }
+                self.groups.retain(|_, group| !group.members.is_empty());
+                return element.is_some();
+            }
+        };
+        match self.children.get_mut(*segment) {
+            Some(child) => {
+                let removed = child.remove(rest, pub_key, group);
+                if child.is_empty() {
+                    self.children.remove(*segment);
+                }
+                removed
+            }
+            None => false,
+        }
+    }
+
+    /// Check is node has no subscribers and children
+    fn is_empty(&self) -> bool {
+        self.subscribers.is_empty() && self.groups.is_empty() && self.children.is_empty()
+    }
+
+    /// Add node subscribers to matched
+    fn push_match<'a>(&'a self, matched: &mut Vec<TopicMatch<'a>>) {
+        if !self.subscribers.is_empty() || !self.groups.is_empty() {
+            matched.push(TopicMatch {
+                pattern: &self.pattern,
+                subscribers: &self.subscribers,
+                groups: self.groups.values().collect(),
+            });
+        }
+    }
+
+    fn matches<'a>(&'a self, segments: &[&str], matched: &mut Vec<TopicMatch<'a>>) {
+        let (segment, rest) = match segments.split_first() {
+            Some(split) => split,
+            None => return self.push_match(matched),
+        };
+        if let Some(child) = self.children.get(*segment) {
+            child.matches(rest, matched);
+        }
+        if let Some(child) = self.children.get(SINGLE_WILDCARD) {
+            child.matches(rest, matched);
+        }
+        for wildcard in MULTI_WILDCARDS {
+            if let Some(child) = self.children.get(*wildcard) {
+                child.push_match(matched);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(n: u8) -> PublicKey {
+        PublicKey([n; 32])
+    }
+
+    /// Matched patterns with subscribers and queue groups members
+    fn matched(trie: &TopicTrie, name: &str) -> Vec<(String, Vec<u8>)> {
+        let mut matched: Vec<(String, Vec<u8>)> = trie
+            .matches(name)
+            .into_iter()
+            .map(|m| {
+                let mut keys: Vec<u8> = m.subscribers.iter().map(|pk| pk.0[0]).collect();
+                for group in m.groups {
+                    keys.extend(group.members.iter().map(|pk| pk.0[0]));
+                }
+                (m.pattern.to_owned(), keys)
+            })
+            .collect();
+        matched.sort();
+        matched
+    }
+
+    fn patterns(trie: &TopicTrie, name: &str) -> Vec<String> {
+        matched(trie, name).into_iter().map(|(p, _)| p).collect()
+    }
+
+    #[test]
+    fn matches_exact_pattern() {
+        let mut trie = TopicTrie::default();
+        trie.insert("a.b", key(1), None);
+        assert_eq!(matched(&trie, "a.b"), vec![("a.b".to_owned(), vec![1])]);
+        assert!(matched(&trie, "a").is_empty());
+        assert!(matched(&trie, "a.b.c").is_empty());
+        assert!(matched(&trie, "a.c").is_empty());
+    }
+
+    #[test]
+    fn single_wildcard_matches_one_segment() {
+        let mut trie = TopicTrie::default();
+        trie.insert("a.*.c", key(1), None);
+        trie.insert("*", key(2), None);
+        assert_eq!(patterns(&trie, "a.b.c"), vec!["a.*.c"]);
+        assert_eq!(patterns(&trie, "a.x.c"), vec!["a.*.c"]);
+        assert!(patterns(&trie, "a.c").is_empty());
+        assert!(patterns(&trie, "a.b.b.c").is_empty());
+        assert_eq!(patterns(&trie, "a"), vec!["*"]);
+    }
+
+    #[test]
+    fn multi_wildcards_match_one_or_more_last_segments() {
+        let mut trie = TopicTrie::default();
+        trie.insert("a.>", key(1), None);
+        trie.insert("a.#", key(2), None);
+        assert_eq!(patterns(&trie, "a.b"), vec!["a.#", "a.>"]);
+        assert_eq!(patterns(&trie, "a.b.c.d"), vec!["a.#", "a.>"]);
+        assert!(patterns(&trie, "a").is_empty());
+        assert!(patterns(&trie, "b.c").is_empty());
+    }
+
+    #[test]
+    fn matches_are_grouped_by_pattern() {
+        let mut trie = TopicTrie::default();
+        trie.insert("a.b", key(1), None);
+        trie.insert("a.b", key(2), Some("workers"));
+        trie.insert("a.b", key(3), Some("workers"));
+        trie.insert("a.*", key(4), None);
+        trie.insert(">", key(1), None);
+        assert!(!trie.insert("a.b", key(1), None));
+        assert_eq!(
+            matched(&trie, "a.b"),
+            vec![
+                (">".to_owned(), vec![1]),
+                ("a.*".to_owned(), vec![4]),
+                ("a.b".to_owned(), vec![1, 2, 3]),
+            ]
+        );
+        for (name, pattern) in [("a.b", "a.*"), ("a.b", ">"), ("a.b.c", ">")] {
+            assert!(is_match(pattern, name));
+        }
+        assert!(!is_match("a.*", "a.b.c"));
+        assert!(!is_match("a.>", "a"));
+    }
+
+    #[test]
+    fn valid_patterns_and_names() {
+        for pattern in ["a", "a.b", "*", "a.*.c", ">", "a.>", "a.*.#"] {
+            assert!(is_valid_pattern(pattern), "{}", pattern);
+        }
+        for pattern in ["", ".a", "a.", "a..b", "a.>.b", "#.a", "a.>.>"] {
+            assert!(!is_valid_pattern(pattern), "{}", pattern);
+        }
+        assert!(is_valid_name("a.b.c"));
+        for name in ["", "a.*", "a.>", "a.#", "a..b"] {
+            assert!(!is_valid_name(name), "{}", name);
+        }
+    }
+
+    #[test]
+    fn remove_prunes_empty_nodes() {
+        let mut trie = TopicTrie::default();
+        trie.insert("a.b.c", key(1), None);
+        trie.insert("a.b", key(2), None);
+        trie.insert("a.b", key(3), Some("workers"));
+
+        assert!(!trie.remove("a.b.c", &key(2), None));
+        assert!(!trie.remove("a.x", &key(1), None));
+        assert!(trie.remove("a.b.c", &key(1), None));
+        let b = &trie.root.children["a"].children["b"];
+        assert!(b.children.is_empty());
+
+        // Node is kept while it has queue group members
+        assert!(trie.remove("a.b", &key(2), None));
+        assert!(!trie.is_empty());
+        assert_eq!(patterns(&trie, "a.b"), vec!["a.b"]);
+
+        assert!(trie.remove("a.b", &key(3), Some("workers")));
+        assert!(trie.is_empty());
+        assert!(matched(&trie, "a.b").is_empty());
+    }
+}
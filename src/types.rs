@@ -1,7 +1,9 @@
+use crate::codec;
 use crate::sign;
 use serde_derive::{Deserialize, Serialize};
 use sodiumoxide::crypto::box_;
 use sodiumoxide::crypto::sign::ed25519::{PublicKey, SecretKey};
+use std::collections::HashMap;
 
 /// Basic Node configuration
 #[derive(Serialize, Deserialize, Debug)]
@@ -9,6 +11,238 @@ pub struct NodeConfig {
     pub public_key: String,
     pub secret_key: String,
     pub port: u32,
+    /// Message signature verification policy
+    #[serde(default)]
+    pub signature_policy: SignaturePolicy,
+    /// Max frame size for client connections
+    #[serde(default = "default_max_frame_size")]
+    pub max_frame_size: usize,
+    /// REQ/REP request reply waiting timeout in seconds
+    #[serde(default = "default_request_timeout_sec")]
+    pub request_timeout_sec: u64,
+    /// Message acknowledgement timeout in seconds,
+    /// not acknowledged message is redelivered
+    #[serde(default = "default_ack_timeout_sec")]
+    pub ack_timeout_sec: u64,
+    /// Mailbox for offline clients
+    #[serde(default)]
+    pub mailbox: MailboxConfig,
+    /// Event history for durable subscribers
+    #[serde(default)]
+    pub history: HistoryConfig,
+    /// Duplicate messages detection
+    #[serde(default)]
+    pub dedup: DedupConfig,
+    /// Outbound queue of client sessions
+    #[serde(default)]
+    pub outbound: OutboundConfig,
+    /// Client requests rate limits
+    #[serde(default)]
+    pub rate_limit: RateLimitConfig,
+    /// Write-ahead log, Node state is not persisted if not set
+    pub wal: Option<WalConfig>,
+}
+
+/// Offline clients mailbox configuration
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MailboxConfig {
+    /// Max queued messages per recipient
+    pub capacity: usize,
+    /// Queued message time to live in seconds
+    pub ttl_sec: u64,
+}
+
+impl Default for MailboxConfig {
+    fn default() -> Self {
+        MailboxConfig {
+            capacity: 1000,
+            ttl_sec: 24 * 60 * 60,
+        }
+    }
+}
+
+/// Durable subscriptions event history configuration
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HistoryConfig {
+    /// Max retained PUB messages per event
+    pub capacity: usize,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        HistoryConfig { capacity: 1000 }
+    }
+}
+
+/// Duplicate messages detection configuration
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DedupConfig {
+    /// Accepted message ids remembering time in seconds,
+    /// detection is disabled if zero
+    pub window_sec: u64,
+    /// Max remembered message ids
+    pub capacity: usize,
+}
+
+impl Default for DedupConfig {
+    fn default() -> Self {
+        DedupConfig {
+            window_sec: 60,
+            capacity: 100_000,
+        }
+    }
+}
+
+/// Session outbound queue configuration
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OutboundConfig {
+    /// Max messages waiting for sending to slow client
+    pub capacity: usize,
+    /// Max bytes written to connection and not sent yet,
+    /// messages are queued when exceeded
+    pub window_size: u64,
+    /// Full queue policy
+    #[serde(default)]
+    pub policy: SlowConsumerPolicy,
+}
+
+impl Default for OutboundConfig {
+    fn default() -> Self {
+        OutboundConfig {
+            capacity: 1000,
+            window_size: 256 * 1024,
+            policy: SlowConsumerPolicy::default(),
+        }
+    }
+}
+
+/// Slow client full outbound queue policy
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum SlowConsumerPolicy {
+    /// Drop oldest queued message
+    #[default]
+    DropOldest,
+    /// Drop new message
+    DropNewest,
+    /// Disconnect client, queued messages are
+    /// redelivered when client reconnected
+    Disconnect,
+}
+
+/// Client requests rate limits configuration
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RateLimitConfig {
+    /// Requests per second
+    pub messages_per_sec: u64,
+    /// Message body bytes per second
+    pub bytes_per_sec: u64,
+    /// Rate limits for specific clients by hex PublicKey
+    #[serde(default)]
+    pub overrides: HashMap<String, RateLimit>,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            messages_per_sec: 1000,
+            bytes_per_sec: codec::DEFAULT_MAX_FRAME_SIZE as u64,
+            overrides: HashMap::new(),
+        }
+    }
+}
+
+impl RateLimitConfig {
+    /// Rate limit for clients without override
+    pub fn default_limit(&self) -> RateLimit {
+        RateLimit {
+            messages_per_sec: self.messages_per_sec,
+            bytes_per_sec: self.bytes_per_sec,
+        }
+    }
+
+    /// Rate limit for client
+    pub fn limit(&self, pub_key: &PublicKey) -> RateLimit {
+        match self.overrides.get(&sign::to_hex_pk(pub_key)) {
+            Some(limit) => *limit,
+            None => self.default_limit(),
+        }
+    }
+}
+
+/// Client requests rate limit, zero disables limit.
+/// Message with body larger than `bytes_per_sec`
+/// always exceeds rate limit.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct RateLimit {
+    pub messages_per_sec: u64,
+    pub bytes_per_sec: u64,
+}
+
+/// Write-ahead log configuration
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WalConfig {
+    /// Log segments directory
+    pub path: String,
+    /// Max segment file size in bytes
+    #[serde(default = "default_wal_segment_size")]
+    pub segment_size: u64,
+    /// Segment file sync policy
+    #[serde(default)]
+    pub fsync: FsyncPolicy,
+    /// Log is compacted to current state when
+    /// segments count since last checkpoint exceeds it
+    #[serde(default = "default_wal_checkpoint_segments")]
+    pub checkpoint_segments: u64,
+}
+
+/// Default WAL segment size for config
+fn default_wal_segment_size() -> u64 {
+    64 * 1024 * 1024
+}
+
+/// Default WAL segments count between checkpoints for config
+fn default_wal_checkpoint_segments() -> u64 {
+    4
+}
+
+/// Write-ahead log sync to disk policy
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum FsyncPolicy {
+    /// Sync after every record
+    Always,
+    /// Sync once per second
+    #[default]
+    EverySecond,
+    /// Sync is left to OS
+    Never,
+}
+
+/// Default REQ/REP request timeout for config
+fn default_request_timeout_sec() -> u64 {
+    30
+}
+
+/// Default message acknowledgement timeout for config
+fn default_ack_timeout_sec() -> u64 {
+    10
+}
+
+/// Default max frame size for config
+fn default_max_frame_size() -> usize {
+    codec::DEFAULT_MAX_FRAME_SIZE
+}
+
+/// Node policy for message signature verification
+/// before message routing
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum SignaturePolicy {
+    /// Signatures are not verified
+    #[default]
+    Off,
+    /// Verify signature only if message signed
+    VerifyIfPresent,
+    /// All messages should be signed with valid signature
+    Require,
 }
 
 /// Basic client config
@@ -25,6 +259,12 @@ pub struct ClientConfig {
 pub struct ClientNodeConfig {
     pub ip: String,
     pub port: u32,
+    /// Max frame size for Node connection
+    #[serde(default = "default_max_frame_size")]
+    pub max_frame_size: usize,
+    /// Preferred wire format for Node connection
+    #[serde(default)]
+    pub wire_format: codec::WireFormat,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -57,6 +297,16 @@ pub struct NodeAppConfig {
     pub public_key: PublicKey,
     pub secret_key: SecretKey,
     pub port: u32,
+    pub signature_policy: SignaturePolicy,
+    pub max_frame_size: usize,
+    pub request_timeout_sec: u64,
+    pub ack_timeout_sec: u64,
+    pub mailbox: MailboxConfig,
+    pub history: HistoryConfig,
+    pub dedup: DedupConfig,
+    pub outbound: OutboundConfig,
+    pub rate_limit: RateLimitConfig,
+    pub wal: Option<WalConfig>,
 }
 
 /// Client app config struct
@@ -73,6 +323,8 @@ pub struct ClientAppConfig {
 pub struct ClientAppNodeConfig {
     pub ip: String,
     pub port: u32,
+    pub max_frame_size: usize,
+    pub wire_format: codec::WireFormat,
 }
 
 /// Init Node app configuration
@@ -83,6 +335,16 @@ impl NodeAppConfig {
             public_key: sign::from_string_pk(&cfg.public_key),
             secret_key: sign::from_string_sk(&cfg.secret_key),
             port: cfg.port,
+            signature_policy: cfg.signature_policy,
+            max_frame_size: cfg.max_frame_size,
+            request_timeout_sec: cfg.request_timeout_sec,
+            ack_timeout_sec: cfg.ack_timeout_sec,
+            mailbox: cfg.mailbox.clone(),
+            history: cfg.history.clone(),
+            dedup: cfg.dedup.clone(),
+            outbound: cfg.outbound.clone(),
+            rate_limit: cfg.rate_limit.clone(),
+            wal: cfg.wal.clone(),
         }
     }
 }
@@ -97,6 +359,8 @@ impl ClientAppConfig {
             node: ClientAppNodeConfig {
                 ip: cfg.node.ip.clone(),
                 port: cfg.node.port,
+                max_frame_size: cfg.node.max_frame_size,
+                wire_format: cfg.node.wire_format,
             },
             message: ClientAppMessageConfig {
                 public_key: sign::from_string_box_pk(&cfg.message.public_key),
@@ -0,0 +1,473 @@
+use byteorder::{BigEndian, ByteOrder};
+use serde_derive::{Deserialize, Serialize};
+use sodiumoxide::crypto::sign::ed25519::PublicKey;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::codec::BoxKey;
+use crate::mailbox::QueuedMessage;
+use crate::server::MqMessage;
+use crate::types::{FsyncPolicy, WalConfig};
+
+/// Record header: [u32 payload length][u32 payload CRC32]
+const RECORD_HEADER_SIZE: usize = 8;
+
+/// Segment file extension
+const SEGMENT_EXT: &str = "wal";
+
+/// Node state change stored in write-ahead log
+#[derive(Debug, Serialize, Deserialize)]
+pub enum WalRecord {
+    /// Client registered at least once
+    Registered(PublicKey),
+    /// Client published box_ PublicKey
+    KeyPublished { pub_key: PublicKey, key: BoxKey },
+    /// Client subscribed to event
+    Subscribed {
+        event: String,
+        pub_key: PublicKey,
+        #[serde(default)]
+        durable: bool,
+        #[serde(default)]
+        group: Option<String>,
+    },
+    /// Client unsubscribed from event
+    Unsubscribed { event: String, pub_key: PublicKey },
+    /// Last retained PUB message of event
+    Retained {
+        event: String,
+        message: Box<MqMessage>,
+    },
+    /// Retained PUB message of event cleared
+    Unretained { event: String },
+    /// PUB message retained for durable subscribers
+    Published {
+        event: String,
+        seq: u64,
+        message: Box<MqMessage>,
+    },
+    /// PUB message delivered to durable subscriber
+    Delivered {
+        event: String,
+        pub_key: PublicKey,
+        seq: u64,
+    },
+    /// Message accepted to offline client mailbox
+    Queued {
+        to: PublicKey,
+        queued: Box<QueuedMessage>,
+    },
+    /// Client mailbox delivered to client
+    Dequeued(PublicKey),
+    /// Message handed to online client session,
+    /// kept until session reports it done
+    Sent {
+        to: PublicKey,
+        message: Box<MqMessage>,
+    },
+    /// Message handed to client session is acknowledged
+    /// by client, or dropped or expired by session
+    Acked {
+        to: PublicKey,
+        from: PublicKey,
+        id: String,
+    },
+    /// Message held until delivery time
+    Scheduled(Box<MqMessage>),
+    /// Scheduled message routed or expired
+    Unscheduled { from: PublicKey, id: String },
+    /// Checkpoint state records start
+    CheckpointBegin,
+    /// Checkpoint state records are complete, all
+    /// records before checkpoint are replaced by them
+    CheckpointEnd,
+}
+
+/// Append-only write-ahead log split to segment files.
+///
+/// Each record is written as `[u32 BE length][u32 BE CRC32][payload]`
+/// where payload is MessagePack encoded `WalRecord`.
+pub struct Wal {
+    dir: PathBuf,
+    cfg: WalConfig,
+    /// Current segment sequence number
+    seq: u64,
+    /// Current segment file
+    file: File,
+    /// Current segment size in bytes
+    size: u64,
+    /// Last segment sequence number of last checkpoint
+    checkpoint_seq: u64,
+}
+
+impl Wal {
+    /// Open log at configured directory and read all stored records
+    pub fn open(cfg: &WalConfig) -> io::Result<(Wal, Vec<WalRecord>)> {
+        let dir = PathBuf::from(&cfg.path);
+        fs::create_dir_all(&dir)?;
+
+        let segments = list_segments(&dir)?;
+        let mut records = vec![];
+        for seq in segments.iter() {
+            // Records after corrupted record are not replayed,
+            // state changes can't be applied with gap. Torn
+            // tail is followed by segment started after restart.
+            if !read_segment(&segment_path(&dir, *seq), &mut records)? {
+                break;
+            }
+        }
+        let records = replay_checkpoints(records);
+
+        // Always start new segment, so torn tail
+        // of last segment is never appended to
+        let seq = segments.last().map_or(0, |seq| seq + 1);
+        let file = create_segment(&dir, seq)?;
+        let wal = Wal {
+            dir,
+            cfg: cfg.clone(),
+            seq,
+            file,
+            size: 0,
+            checkpoint_seq: segments.first().cloned().unwrap_or(seq),
+        };
+        Ok((wal, records))
+    }
+
+    /// Append record to current segment
+    pub fn append(&mut self, record: &WalRecord) -> io::Result<()> {
+        let payload = rmp_serde::to_vec_named(record)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+
+        let mut buf = vec![0; RECORD_HEADER_SIZE + payload.len()];
+        BigEndian::write_u32(&mut buf[..4], payload.len() as u32);
+        BigEndian::write_u32(&mut buf[4..RECORD_HEADER_SIZE], checksum(&payload));
+        buf[RECORD_HEADER_SIZE..].copy_from_slice(&payload);
+
+        if self.size > 0 && self.size + buf.len() as u64 > self.cfg.segment_size {
+            self.rotate()?;
+        }
+        self.file.write_all(&buf)?;
+        self.size += buf.len() as u64;
+
+        if self.cfg.fsync == FsyncPolicy::Always {
+            self.file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// Flush current segment to disk
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+
+    /// Is log grown enough since last checkpoint to be compacted
+    pub fn needs_checkpoint(&self) -> bool {
+        self.seq - self.checkpoint_seq >= self.cfg.checkpoint_segments.max(1)
+    }
+
+    /// Replace all segments with new segments
+    /// which contain only current state records
+    pub fn checkpoint(&mut self, records: &[WalRecord]) -> io::Result<()> {
+        let first_seq = self.write_checkpoint(records)?;
+        // Old segments are ignored by replay if node
+        // crashed before they were removed
+        for seq in list_segments(&self.dir)? {
+            if seq < first_seq {
+                fs::remove_file(segment_path(&self.dir, seq))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write state records between checkpoint markers
+    /// to new segments, returns first checkpoint segment.
+    fn write_checkpoint(&mut self, records: &[WalRecord]) -> io::Result<u64> {
+        self.rotate()?;
+        // State records can be split to several segments
+        let first_seq = self.seq;
+        self.append(&WalRecord::CheckpointBegin)?;
+        for record in records {
+            self.append(record)?;
+        }
+        self.append(&WalRecord::CheckpointEnd)?;
+        self.file.sync_all()?;
+        self.checkpoint_seq = self.seq;
+        Ok(first_seq)
+    }
+
+    /// Close current segment and start new one
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.sync_all()?;
+        self.seq += 1;
+        self.file = create_segment(&self.dir, self.seq)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+/// Records of last complete checkpoint and records
+/// appended after it. Records of incomplete checkpoint
+/// are dropped, they only repeat state before it.
+fn replay_checkpoints(records: Vec<WalRecord>) -> Vec<WalRecord> {
+    let mut replayed = vec![];
+    let mut checkpoint: Option<Vec<WalRecord>> = None;
+    for record in records {
+        match record {
+            WalRecord::CheckpointBegin => checkpoint = Some(vec![]),
+            WalRecord::CheckpointEnd => {
+                if let Some(state) = checkpoint.take() {
+                    replayed = state;
+                }
+            }
+            record => match checkpoint.as_mut() {
+                Some(state) => state.push(record),
+                None => replayed.push(record),
+            },
+        }
+    }
+    replayed
+}
+
+/// Record payload CRC32
+fn checksum(payload: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(payload);
+    hasher.finalize()
+}
+
+/// Segment file path for sequence number
+fn segment_path(dir: &Path, seq: u64) -> PathBuf {
+    dir.join(format!("{:020}.{}", seq, SEGMENT_EXT))
+}
+
+/// Create new segment file
+fn create_segment(dir: &Path, seq: u64) -> io::Result<File> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(segment_path(dir, seq))?;
+    // Persist new directory entry
+    File::open(dir)?.sync_all()?;
+    Ok(file)
+}
+
+/// Sorted segment sequence numbers in log directory
+fn list_segments(dir: &Path) -> io::Result<Vec<u64>> {
+    let mut segments = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|ext| ext.to_str()) != Some(SEGMENT_EXT) {
+            continue;
+        }
+        if let Some(seq) = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| stem.parse::<u64>().ok())
+        {
+            segments.push(seq);
+        }
+    }
+    segments.sort();
+    Ok(segments)
+}
+
+/// Read segment records. Reading is stopped at first
+/// incomplete record, it can be left by node crash
+/// during write. Returns false if corrupted record found.
+fn read_segment(path: &Path, records: &mut Vec<WalRecord>) -> io::Result<bool> {
+    let mut data = vec![];
+    File::open(path)?.read_to_end(&mut data)?;
+
+    let mut pos = 0;
+    while pos < data.len() {
+        if data.len() - pos < RECORD_HEADER_SIZE {
+            eprintln!("WAL {:?}: incomplete record header at {}", path, pos);
+            break;
+        }
+        let size = BigEndian::read_u32(&data[pos..pos + 4]) as usize;
+        let crc = BigEndian::read_u32(&data[pos + 4..pos + RECORD_HEADER_SIZE]);
+        let start = pos + RECORD_HEADER_SIZE;
+        if data.len() - start < size {
+            eprintln!("WAL {:?}: incomplete record at {}", path, pos);
+            break;
+        }
+        let payload = &data[start..start + size];
+        if checksum(payload) != crc {
+            eprintln!("WAL {:?}: invalid record checksum at {}", path, pos);
+            return Ok(false);
+        }
+        match rmp_serde::from_slice(payload) {
+            Ok(record) => records.push(record),
+            Err(err) => {
+                eprintln!("WAL {:?}: invalid record at {}: {}", path, pos, err);
+                return Ok(false);
+            }
+        }
+        pos = start + size;
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    /// Empty log directory for test
+    fn config(name: &str, segment_size: u64) -> WalConfig {
+        let dir = std::env::temp_dir().join(format!("amq-wal-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        WalConfig {
+            path: dir.to_string_lossy().into_owned(),
+            segment_size,
+            fsync: FsyncPolicy::Never,
+            checkpoint_segments: 2,
+        }
+    }
+
+    fn registered(n: u8) -> WalRecord {
+        WalRecord::Registered(PublicKey([n; 32]))
+    }
+
+    /// Registered clients of records
+    fn keys(records: &[WalRecord]) -> Vec<u8> {
+        records
+            .iter()
+            .map(|record| match record {
+                WalRecord::Registered(pub_key) => pub_key.0[0],
+                _ => panic!("unexpected record: {:?}", record),
+            })
+            .collect()
+    }
+
+    /// Corrupt byte at position from end of segment file
+    fn corrupt(cfg: &WalConfig, seq: u64, from_end: u64) {
+        let path = segment_path(Path::new(&cfg.path), seq);
+        let mut data = fs::read(&path).unwrap();
+        let pos = data.len() - from_end as usize;
+        data[pos] ^= 0xff;
+        fs::write(&path, data).unwrap();
+    }
+
+    #[test]
+    fn append_and_reopen() {
+        let cfg = config("reopen", 1024);
+        let (mut wal, records) = Wal::open(&cfg).unwrap();
+        assert!(records.is_empty());
+        for n in 1..=3 {
+            wal.append(&registered(n)).unwrap();
+        }
+        drop(wal);
+
+        let (mut wal, records) = Wal::open(&cfg).unwrap();
+        assert_eq!(keys(&records), vec![1, 2, 3]);
+        wal.append(&registered(4)).unwrap();
+        drop(wal);
+
+        let (_, records) = Wal::open(&cfg).unwrap();
+        assert_eq!(keys(&records), vec![1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn segment_rotation_and_checkpoint() {
+        // Every record is written to own segment
+        let cfg = config("rotation", 1);
+        let (mut wal, _) = Wal::open(&cfg).unwrap();
+        for n in 1..=3 {
+            wal.append(&registered(n)).unwrap();
+        }
+        let dir = Path::new(&cfg.path);
+        assert_eq!(list_segments(dir).unwrap(), vec![0, 1, 2]);
+        assert!(wal.needs_checkpoint());
+
+        // State records with checkpoint markers
+        wal.checkpoint(&[registered(2), registered(3)]).unwrap();
+        assert_eq!(list_segments(dir).unwrap(), vec![3, 4, 5, 6]);
+        assert!(!wal.needs_checkpoint());
+        drop(wal);
+
+        let (_, records) = Wal::open(&cfg).unwrap();
+        assert_eq!(keys(&records), vec![2, 3]);
+    }
+
+    #[test]
+    fn torn_tail_is_skipped() {
+        let cfg = config("torn", 1024);
+        let (mut wal, _) = Wal::open(&cfg).unwrap();
+        wal.append(&registered(1)).unwrap();
+        wal.append(&registered(2)).unwrap();
+        let seq = wal.seq;
+        drop(wal);
+
+        // Node crashed during last record write
+        let path = segment_path(Path::new(&cfg.path), seq);
+        let size = fs::metadata(&path).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(size - 3)
+            .unwrap();
+
+        let (mut wal, records) = Wal::open(&cfg).unwrap();
+        assert_eq!(keys(&records), vec![1]);
+        // Torn segment is not appended to
+        wal.append(&registered(3)).unwrap();
+        drop(wal);
+
+        let (_, records) = Wal::open(&cfg).unwrap();
+        assert_eq!(keys(&records), vec![1, 3]);
+    }
+
+    #[test]
+    fn checksum_mismatch_stops_replay() {
+        let cfg = config("checksum", 1);
+        let (mut wal, _) = Wal::open(&cfg).unwrap();
+        for n in 1..=3 {
+            wal.append(&registered(n)).unwrap();
+        }
+        drop(wal);
+
+        // Second record payload is corrupted,
+        // third record in next segment is valid
+        corrupt(&cfg, 1, 1);
+        let (_, records) = Wal::open(&cfg).unwrap();
+        assert_eq!(keys(&records), vec![1]);
+    }
+
+    #[test]
+    fn checkpoint_replaces_old_segments_not_removed() {
+        let cfg = config("checkpoint-crash", 1024);
+        let (mut wal, _) = Wal::open(&cfg).unwrap();
+        wal.append(&registered(1)).unwrap();
+        wal.append(&registered(2)).unwrap();
+        // Node crashed before old segments removed
+        wal.write_checkpoint(&[registered(1), registered(2)])
+            .unwrap();
+        wal.append(&registered(3)).unwrap();
+        drop(wal);
+
+        let (_, records) = Wal::open(&cfg).unwrap();
+        assert_eq!(keys(&records), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn incomplete_checkpoint_is_dropped() {
+        let cfg = config("checkpoint-torn", 1024);
+        let (mut wal, _) = Wal::open(&cfg).unwrap();
+        wal.append(&registered(1)).unwrap();
+        // Node crashed while checkpoint written
+        wal.append(&WalRecord::CheckpointBegin).unwrap();
+        wal.append(&registered(1)).unwrap();
+        drop(wal);
+
+        let (mut wal, records) = Wal::open(&cfg).unwrap();
+        assert_eq!(keys(&records), vec![1]);
+        wal.checkpoint(&records).unwrap();
+        drop(wal);
+
+        let (_, records) = Wal::open(&cfg).unwrap();
+        assert_eq!(keys(&records), vec![1]);
+    }
+}